@@ -0,0 +1,558 @@
+//! Agent holding unlocked credentials for other cred-lock processes
+//!
+//! Each `cred-lock get` otherwise opens the key store afresh, which may mean
+//! unlocking it again. `cred-lock agent` unlocks the store once and serves the
+//! credentials it fetches from memory, locked against swapping, over a Unix
+//! socket only the same user can connect to. It exits once idle for a while,
+//! or when `cred-lock lock` locks the key store through it.
+//!
+//! The agent doesn't notice the key store locking itself, such as the vault
+//! going idle or a keyring unlock expiring, and keeps serving what it holds
+//! until then. Keep its idle timeout within the store's own lock interval.
+//!
+//! Other cred-lock processes fetch credentials from a running agent and fall
+//! back to the key store itself when there isn't one, or it can't help. After
+//! changing stored credentials they tell the agent to forget what it holds.
+//!
+//! The agent runs in the foreground and never detaches itself. Run it under a
+//! supervisor, such as a systemd user service or a launchd agent, or put it in
+//! the background from a shell with `cred-lock agent &`.
+//!
+//! The socket's directory must belong to the user and be closed to everyone else,
+//! as whoever controls it could stand in a socket of their own. The agent refuses
+//! to listen, and other cred-lock processes to connect, in any other directory.
+
+use crate::{
+    error::Failure,
+    secret::Secret,
+    store::{Backend, CredentialStore, Entry, Listing, DEFAULT_CHAIN},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fs::{self, DirBuilder},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+use zeroize::Zeroizing;
+
+/// Longest request or response line read from the socket
+const MAX_MESSAGE: u64 = 1 << 20;
+/// How long a client waits on the agent before giving up
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the agent waits on a client. Connections are served one at a time,
+/// so this bounds how long a stalled client holds up the others
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often an idle agent checks for connections
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Request {
+    /// Every set of credentials stored for a profile
+    Fetch(String),
+    /// Drop all held credentials, as stored ones have changed
+    Forget,
+    /// Drop all held credentials and exit, as the key store was locked
+    Stop,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Response {
+    Entries(Vec<Held>),
+    Done,
+    /// The agent couldn't serve the request, so it should be served without it
+    Failed,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Held {
+    access_key_id: String,
+    secret_access_key: Secret,
+}
+
+/// Path of the agent socket for a key store, overridable with `CRED_LOCK_AGENT_SOCK`
+pub fn socket_path(backend: Option<Backend>) -> Result<PathBuf, Box<dyn Error>> {
+    match env::var_os("CRED_LOCK_AGENT_SOCK") {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(dirs::runtime_dir()
+            .or_else(dirs::cache_dir)
+            .ok_or("unable to resolve a directory for the agent socket")?
+            .join("cred-lock")
+            .join(format!(
+                "{}-{}.sock",
+                DEFAULT_CHAIN,
+                backend.unwrap_or_default()
+            ))),
+    }
+}
+
+/// Unlocks a key store and serves credentials from it on a socket until no requests
+/// arrive for `idle_timeout` or `cred-lock lock` stops it. Only connections from
+/// processes running as the same user are served
+pub fn serve(
+    store: &mut dyn CredentialStore,
+    socket: &Path,
+    idle_timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    store.unlock()?;
+    let listener = Listening::bind(socket)?;
+    let mut held: HashMap<String, Vec<Entry>> = HashMap::new();
+    let mut last_request = Instant::now();
+    loop {
+        let stream = match listener.0.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if last_request.elapsed() >= idle_timeout {
+                    return Ok(());
+                }
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // connections from other users, or whose user can't be told, are dropped unanswered
+        match peer_uid(&stream) {
+            Ok(uid) if uid == unsafe { libc::geteuid() } => (),
+            _ => continue,
+        }
+        last_request = Instant::now();
+        let mut connection = Connection::new(stream, CLIENT_TIMEOUT)?;
+        let request = match connection.receive::<Request>() {
+            Ok(request) => request,
+            Err(_) => continue,
+        };
+        let response = match &request {
+            Request::Fetch(profile) => match fetch(store, &mut held, profile) {
+                Ok(entries) => Response::Entries(entries),
+                Err(_) => Response::Failed,
+            },
+            Request::Forget | Request::Stop => {
+                held.clear();
+                Response::Done
+            }
+        };
+        // a client going away mid response is no reason to stop serving others
+        let _ = connection.send(&response);
+        if request == Request::Stop {
+            return Ok(());
+        }
+    }
+}
+
+/// Fetches a profile's credentials, holding on to them for later requests
+fn fetch(
+    store: &mut dyn CredentialStore,
+    held: &mut HashMap<String, Vec<Entry>>,
+    profile: &str,
+) -> Result<Vec<Held>, Box<dyn Error>> {
+    if !held.contains_key(profile) {
+        let entries = store.fetch(profile)?;
+        // profiles yet to be stored are looked up again next time
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        held.insert(profile.into(), entries);
+    }
+    Ok(held[profile]
+        .iter()
+        .map(|entry| Held {
+            access_key_id: entry.access_key_id.clone(),
+            secret_access_key: entry.secret_access_key.clone(),
+        })
+        .collect())
+}
+
+/// A bound agent socket, removed once the agent stops
+struct Listening(UnixListener, PathBuf);
+
+impl Listening {
+    fn bind(socket: &Path) -> Result<Self, Box<dyn Error>> {
+        if let Some(parent) = socket.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+        check_private(socket)?;
+        if socket.exists() {
+            if UnixStream::connect(socket).is_ok() {
+                return Err(
+                    format!("an agent is already listening on {}", socket.display()).into(),
+                );
+            }
+            // left behind by an agent that didn't stop cleanly
+            fs::remove_file(socket)?;
+        }
+        let listener = UnixListener::bind(socket)?;
+        let listening = Listening(listener, socket.into());
+        fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+        listening.0.set_nonblocking(true)?;
+        Ok(listening)
+    }
+}
+
+impl Drop for Listening {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.1);
+    }
+}
+
+/// Checks a socket's directory belongs to the current user and only they can use it.
+/// Creating the directory only sets its mode when it doesn't already exist
+fn check_private(socket: &Path) -> Result<(), Box<dyn Error>> {
+    let parent = match socket.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    let metadata = fs::metadata(parent)?;
+    // safety: geteuid never fails
+    if metadata.uid() != unsafe { libc::geteuid() } || metadata.mode() & 0o077 != 0 {
+        return Err(Failure::InvalidInput(format!(
+            "{} must belong to you and be inaccessible to others to hold the agent socket, \
+             such as with `chmod 700 {}`",
+            parent.display(),
+            parent.display()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// User id of the process on the other end of a socket
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // safety: credentials and length describe a ucred the kernel writes into
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+/// User id of the process on the other end of a socket
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let (mut uid, mut gid) = (0, 0);
+    // safety: uid and gid are valid for writes
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// One request and its response, each a line of json
+struct Connection {
+    reader: BufReader<io::Take<UnixStream>>,
+    writer: UnixStream,
+    /// Longest the other end has to send a whole message, however slowly it arrives
+    timeout: Duration,
+}
+
+impl Connection {
+    fn new(
+        stream: UnixStream,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Connection {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream.take(MAX_MESSAGE)),
+            timeout,
+        })
+    }
+
+    fn send<T: Serialize>(
+        &mut self,
+        message: &T,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut line = Zeroizing::new(serde_json::to_string(message)?);
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    fn receive<T: for<'de> Deserialize<'de>>(&mut self) -> Result<T, Box<dyn Error>> {
+        let deadline = Instant::now() + self.timeout;
+        let mut line = Zeroizing::new(Vec::new());
+        loop {
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .filter(|remaining| !remaining.is_zero())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::TimedOut))?;
+            // both halves share one socket, and with it the timeout
+            self.writer.set_read_timeout(Some(remaining))?;
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                break;
+            }
+            let (length, complete) = match available.iter().position(|&byte| byte == b'\n') {
+                Some(end) => (end + 1, true),
+                None => (available.len(), false),
+            };
+            line.extend_from_slice(&available[..length]);
+            self.reader.consume(length);
+            if complete {
+                break;
+            }
+        }
+        Ok(serde_json::from_slice(&line)?)
+    }
+}
+
+/// Sends a request to the agent listening on a socket, if there is one
+fn request(
+    socket: &Path,
+    request: &Request,
+) -> Result<Response, Box<dyn Error>> {
+    check_private(socket)?;
+    let mut connection = Connection::new(UnixStream::connect(socket)?, TIMEOUT)?;
+    connection.send(request)?;
+    connection.receive()
+}
+
+/// A key store whose credentials are fetched through an agent while one is running
+pub struct Attached {
+    store: Box<dyn CredentialStore>,
+    socket: PathBuf,
+}
+
+impl Attached {
+    pub fn new(
+        store: Box<dyn CredentialStore>,
+        socket: PathBuf,
+    ) -> Self {
+        Attached { store, socket }
+    }
+
+    /// Tells a running agent its credentials are out of date
+    fn forget(&self) {
+        let _ = request(&self.socket, &Request::Forget);
+    }
+}
+
+impl CredentialStore for Attached {
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        self.store.init()
+    }
+
+    fn put(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        let result = self.store.put(entry);
+        self.forget();
+        result
+    }
+
+    fn fetch(
+        &mut self,
+        profile: &str,
+    ) -> Result<Vec<Entry>, Box<dyn Error>> {
+        match request(&self.socket, &Request::Fetch(profile.into())) {
+            Ok(Response::Entries(entries)) if !entries.is_empty() => Ok(entries
+                .into_iter()
+                .map(|held| Entry {
+                    profile: profile.into(),
+                    access_key_id: held.access_key_id,
+                    secret_access_key: held.secret_access_key,
+                })
+                .collect()),
+            _ => self.store.fetch(profile),
+        }
+    }
+
+    fn delete(
+        &mut self,
+        profile: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let result = self.store.delete(profile);
+        self.forget();
+        result
+    }
+
+    fn replace(
+        &mut self,
+        old: &Listing,
+        new: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        let result = self.store.replace(old, new);
+        self.forget();
+        result
+    }
+
+    fn list(&mut self) -> Result<Vec<Listing>, Box<dyn Error>> {
+        self.store.list()
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn Error>> {
+        let _ = request(&self.socket, &Request::Stop);
+        self.store.lock()
+    }
+
+    fn unlock(&mut self) -> Result<(), Box<dyn Error>> {
+        self.store.unlock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::{entry, Memory};
+
+    /// A directory only the current user can use, as the agent requires of its socket's
+    fn private_dir() -> io::Result<tempfile::TempDir> {
+        let dir = tempfile::tempdir()?;
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700))?;
+        Ok(dir)
+    }
+
+    /// Waits for an agent to start listening
+    fn listening(socket: &Path) {
+        while UnixStream::connect(socket).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn serves_held_credentials_until_locked() -> Result<(), Box<dyn Error>> {
+        let dir = private_dir()?;
+        let socket = dir.path().join("agent").join("test.sock");
+        let agent = {
+            let socket = socket.clone();
+            thread::spawn(move || {
+                let mut store = Memory {
+                    entries: vec![entry("dev", "AKIAAGENT")],
+                    ..Memory::default()
+                };
+                serve(&mut store, &socket, Duration::from_secs(30)).map_err(|e| e.to_string())
+            })
+        };
+        listening(&socket);
+        assert_eq!(fs::metadata(&socket)?.permissions().mode() & 0o777, 0o600);
+        assert_eq!(
+            fs::metadata(socket.parent().unwrap())?.permissions().mode() & 0o777,
+            0o700
+        );
+
+        // held credentials win over the local store, which falls in when the agent has none
+        let mut attached = Attached::new(
+            Box::new(Memory {
+                entries: vec![entry("dev", "AKIALOCAL"), entry("prod", "AKIAPROD")],
+                ..Memory::default()
+            }),
+            socket.clone(),
+        );
+        assert_eq!(attached.fetch("dev")?, vec![entry("dev", "AKIAAGENT")]);
+        assert_eq!(attached.fetch("prod")?, vec![entry("prod", "AKIAPROD")]);
+
+        attached.put(entry("staging", "AKIASTAGING"))?;
+        assert_eq!(attached.fetch("dev")?, vec![entry("dev", "AKIAAGENT")]);
+
+        // locking stops the agent and removes its socket
+        attached.lock()?;
+        agent.join().unwrap()?;
+        assert!(!socket.exists());
+        assert!(attached.fetch("dev").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn exits_when_idle_and_replaces_stale_sockets() -> Result<(), Box<dyn Error>> {
+        let dir = private_dir()?;
+        let socket = dir.path().join("test.sock");
+        drop(UnixListener::bind(&socket)?);
+        assert!(socket.exists());
+        let started = Instant::now();
+        serve(&mut Memory::default(), &socket, Duration::from_millis(200))?;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(!socket.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn stalled_clients_hold_up_others_briefly() -> Result<(), Box<dyn Error>> {
+        let dir = private_dir()?;
+        let socket = dir.path().join("test.sock");
+        let agent = {
+            let socket = socket.clone();
+            thread::spawn(move || {
+                let mut store = Memory {
+                    entries: vec![entry("dev", "AKIAAGENT")],
+                    ..Memory::default()
+                };
+                serve(&mut store, &socket, Duration::from_secs(30)).map_err(|e| e.to_string())
+            })
+        };
+        listening(&socket);
+        // connects, then neither sends a request nor goes away
+        let _stalled = UnixStream::connect(&socket)?;
+        let mut attached = Attached::new(Box::new(Memory::default()), socket.clone());
+        let started = Instant::now();
+        assert_eq!(attached.fetch("dev")?, vec![entry("dev", "AKIAAGENT")]);
+        assert!(started.elapsed() < TIMEOUT / 2);
+        attached.lock()?;
+        agent.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_sockets_in_directories_open_to_others() -> Result<(), Box<dyn Error>> {
+        let dir = private_dir()?;
+        let shared = dir.path().join("shared");
+        DirBuilder::new().mode(0o755).create(&shared)?;
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o755))?;
+        let socket = shared.join("test.sock");
+
+        let error = serve(&mut Memory::default(), &socket, Duration::from_secs(1))
+            .err()
+            .ok_or("expected an error")?;
+        assert!(crate::error::is(error.as_ref(), Failure::InvalidInput));
+        assert!(!socket.exists());
+
+        // clients never trust a socket someone else could have put there
+        let _planted = UnixListener::bind(&socket)?;
+        let mut attached = Attached::new(
+            Box::new(Memory {
+                entries: vec![entry("dev", "AKIALOCAL")],
+                ..Memory::default()
+            }),
+            socket,
+        );
+        assert_eq!(attached.fetch("dev")?, vec![entry("dev", "AKIALOCAL")]);
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_start_alongside_a_running_agent() -> Result<(), Box<dyn Error>> {
+        let dir = private_dir()?;
+        let socket = dir.path().join("test.sock");
+        let _running = UnixListener::bind(&socket)?;
+        assert!(serve(&mut Memory::default(), &socket, Duration::from_secs(1)).is_err());
+        assert!(socket.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+//! Replacing files that several cred-lock processes may be updating at once,
+//! as the aws cli and sdks run credential processes concurrently
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Replaces a file's contents in one step, by way of a temporary file of its own
+/// in the same directory so that concurrent writers never share one
+pub fn write(
+    path: &Path,
+    contents: &[u8],
+    mode: u32,
+) -> io::Result<()> {
+    let tmp = sibling(
+        path,
+        &format!("{}.{:016x}.tmp", process::id(), rand::random::<u64>()),
+    );
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// An advisory lock on a file, held until dropped, for reading, changing and
+/// writing it back without losing another process's changes
+pub struct Lock(File);
+
+impl Lock {
+    /// Waits for exclusive use of a file, by way of a lock file beside it
+    pub fn exclusive(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(sibling(path, "lock"))?;
+        // safety: the descriptor stays open for the duration of the call
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Lock(file))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // safety: the descriptor is open until the file drops after this
+        unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// A hidden file beside another, named after it with an extra extension
+fn sibling(
+    path: &Path,
+    extension: &str,
+) -> PathBuf {
+    let name = path
+        .file_name()
+        .map_or_else(Default::default, |name| name.to_string_lossy());
+    path.with_file_name(format!(".{}.{}", name, extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{error::Error, thread};
+
+    #[test]
+    fn concurrent_writers_each_replace_the_whole_file() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("file.json");
+        let writers = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                thread::spawn(move || -> io::Result<()> {
+                    for _ in 0..20 {
+                        write(&path, format!("writer {}", i).repeat(100).as_bytes(), 0o600)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+        let contents = fs::read_to_string(&path)?;
+        assert_eq!(contents.len(), 800);
+        assert_eq!(contents, contents[..8].repeat(100));
+        // only the file itself is left behind
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+}
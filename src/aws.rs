@@ -0,0 +1,311 @@
+//! Minimal client for AWS query protocol apis, signed with Signature Version 4
+//! <https://docs.aws.amazon.com/general/latest/gr/sigv4_signing.html>
+
+use crate::Credentials;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use url::Url;
+use zeroize::Zeroizing;
+
+const CONTENT_TYPE: &str = "application/x-www-form-urlencoded; charset=utf-8";
+
+/// A regional endpoint for an AWS query protocol service
+pub struct Endpoint {
+    url: Url,
+    region: String,
+    service: &'static str,
+    version: &'static str,
+}
+
+impl Endpoint {
+    /// STS endpoint, defaulting to the global one
+    pub fn sts(url: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        Endpoint::new(
+            url.unwrap_or("https://sts.amazonaws.com"),
+            "sts",
+            "2011-06-15",
+        )
+    }
+
+    /// IAM endpoint, defaulting to the global one
+    pub fn iam(url: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        Endpoint::new(
+            url.unwrap_or("https://iam.amazonaws.com"),
+            "iam",
+            "2010-05-08",
+        )
+    }
+
+    fn new(
+        url: &str,
+        service: &'static str,
+        version: &'static str,
+    ) -> Result<Self, Box<dyn Error>> {
+        let url = Url::parse(url)?;
+        // regional endpoints are named <service>.<region>.amazonaws.com,
+        // anything else is signed for the global partition's region
+        let region = url
+            .host_str()
+            .and_then(|host| host.strip_suffix(".amazonaws.com"))
+            .and_then(|host| host.strip_prefix(service))
+            .and_then(|host| host.strip_prefix('.'))
+            .unwrap_or("us-east-1")
+            .to_string();
+        Ok(Endpoint {
+            url,
+            region,
+            service,
+            version,
+        })
+    }
+
+    /// Calls an api action, returning its xml response, which may carry secrets
+    pub fn call(
+        &self,
+        credentials: &Credentials,
+        action: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Zeroizing<String>, Box<dyn Error>> {
+        let mut form = vec![("Action", action), ("Version", self.version)];
+        form.extend_from_slice(params);
+        let body = form
+            .iter()
+            .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let host = match (self.url.host_str(), self.url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => return Err(format!("invalid {} endpoint {}", self.service, self.url).into()),
+        };
+        let now = Utc::now();
+        let mut headers = vec![
+            ("content-type", CONTENT_TYPE.to_string()),
+            ("host", host),
+            ("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.expose().into()));
+        }
+        let authorization = authorization(
+            "POST",
+            self.url.path(),
+            &headers,
+            body.as_bytes(),
+            credentials,
+            &self.region,
+            self.service,
+            now,
+        );
+        let mut request = ureq::post(self.url.as_str()).set("authorization", &authorization);
+        for (name, value) in &headers {
+            if *name != "host" {
+                request = request.set(name, value);
+            }
+        }
+        match request.send_string(&body) {
+            Ok(response) => Ok(Zeroizing::new(response.into_string()?)),
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string()?;
+                Err(match (text(&body, "Code"), text(&body, "Message")) {
+                    (Ok(code), Ok(message)) => format!("{} {}: {}", action, code, message),
+                    _ => format!("{} failed with status {}", action, status),
+                }
+                .into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Returns the text of the first element named `tag` in an xml document
+pub fn text(
+    document: &str,
+    tag: &str,
+) -> Result<String, Box<dyn Error>> {
+    Ok(roxmltree::Document::parse(document)?
+        .descendants()
+        .find(|node| node.has_tag_name(tag))
+        .and_then(|node| node.text())
+        .ok_or_else(|| format!("response is missing {}", tag))?
+        .to_string())
+}
+
+/// Percent encodes a value as required for signing
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn hmac(
+    key: &[u8],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Builds the Authorization header for a request without a query string.
+/// Headers are expected to be lower case and sorted by name.
+#[allow(clippy::too_many_arguments)]
+fn authorization(
+    method: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    payload: &[u8],
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    now: DateTime<Utc>,
+) -> String {
+    let date = now.format("%Y%m%d").to_string();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        method,
+        path,
+        headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect::<String>(),
+        signed_headers,
+        hex::encode(Sha256::digest(payload))
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        now.format("%Y%m%dT%H%M%SZ"),
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = [region, service, "aws4_request"].iter().fold(
+        hmac(
+            Zeroizing::new(format!("AWS4{}", credentials.secret_access_key.expose())).as_bytes(),
+            date.as_bytes(),
+        ),
+        |key, part| hmac(&key, part.as_bytes()),
+    );
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex::encode(hmac(&key, string_to_sign.as_bytes()))
+    )
+}
+
+/// A local stand-in for AWS endpoints
+#[cfg(test)]
+pub mod mock {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    /// Serves each (status, body) response to one request in turn, returning
+    /// the server's url and a handle yielding the request bodies it received
+    pub fn serve(responses: Vec<(u16, String)>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind mock server");
+        let url = format!("http://{}/", listener.local_addr().expect("mock address"));
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().expect("failed to accept request");
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).expect("failed to read request");
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().expect("invalid content-length");
+                        }
+                    }
+                }
+                let mut request = vec![0; length];
+                reader
+                    .read_exact(&mut request)
+                    .expect("failed to read body");
+                requests.push(String::from_utf8(request).expect("utf8 request"));
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Mock\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .expect("failed to write response");
+            }
+            requests
+        });
+        (url, handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn signs_requests_like_the_sigv4_test_suite() {
+        // get-vanilla from the aws sigv4 test suite
+        let credentials = Credentials {
+            version: 1,
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            session_token: None,
+            expiration: None,
+        };
+        assert_eq!(
+            authorization(
+                "GET",
+                "/",
+                &[
+                    ("host", "example.amazonaws.com".into()),
+                    ("x-amz-date", "20150830T123600Z".into())
+                ],
+                b"",
+                &credentials,
+                "us-east-1",
+                "service",
+                Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap(),
+            ),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn resolves_signing_region_from_endpoint() -> Result<(), Box<dyn Error>> {
+        assert_eq!(Endpoint::sts(None)?.region, "us-east-1");
+        assert_eq!(
+            Endpoint::sts(Some("https://sts.eu-west-1.amazonaws.com"))?.region,
+            "eu-west-1"
+        );
+        assert_eq!(
+            Endpoint::sts(Some("http://localhost:4566"))?.region,
+            "us-east-1"
+        );
+        Ok(())
+    }
+}
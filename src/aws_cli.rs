@@ -0,0 +1,242 @@
+//! The aws cli's config and shared credentials files, which cred-lock points
+//! profiles at itself in and imports credentials out of
+
+use crate::{atomic, error::Failure, ini, store::Backend};
+use std::{env, error::Error, fs, io, os::unix::fs::PermissionsExt, path::PathBuf};
+
+/// Path of the aws cli credentials file, overridable with `AWS_SHARED_CREDENTIALS_FILE`
+pub fn credentials_path() -> Result<PathBuf, Box<dyn Error>> {
+    match env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(dirs::home_dir()
+            .ok_or("unable to resolve a home directory")?
+            .join(".aws")
+            .join("credentials")),
+    }
+}
+
+/// The aws cli config file, in which profiles get their credentials from
+/// `cred-lock get` run against a given key store
+pub struct AwsConfig {
+    path: PathBuf,
+    backend: Option<Backend>,
+}
+
+impl AwsConfig {
+    /// The aws cli's own config file, overridable with `AWS_CONFIG_FILE`
+    pub fn new(backend: Option<Backend>) -> Result<Self, Box<dyn Error>> {
+        let path = match env::var_os("AWS_CONFIG_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => dirs::home_dir()
+                .ok_or("unable to resolve a home directory")?
+                .join(".aws")
+                .join("config"),
+        };
+        Ok(AwsConfig::at(path, backend))
+    }
+
+    pub fn at(
+        path: PathBuf,
+        backend: Option<Backend>,
+    ) -> Self {
+        AwsConfig { path, backend }
+    }
+
+    fn read(&self) -> Result<ini::Document, Box<dyn Error>> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => Ok(ini::Document::parse(&text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(ini::Document::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the config in one step, so a crash or full disk never leaves the
+    /// user's hand-edited file truncated. An existing file keeps its mode
+    fn write(
+        &self,
+        document: &ini::Document,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mode = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.permissions().mode() & 0o7777,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0o600,
+            Err(e) => return Err(e.into()),
+        };
+        atomic::write(&self.path, document.to_string().as_bytes(), mode)?;
+        Ok(())
+    }
+
+    /// The credential_process running `cred-lock get` for a profile
+    fn command(
+        &self,
+        profile: &str,
+    ) -> String {
+        command(self.backend, profile)
+    }
+
+    /// Sets a profile's credential_process to `cred-lock get`
+    pub fn configure(
+        &self,
+        profile: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut document = self.read()?;
+        document.set(
+            &section(profile),
+            "credential_process",
+            &self.command(profile),
+        );
+        self.write(&document)
+    }
+
+    /// Whether the config has a section for a profile
+    pub fn contains(
+        &self,
+        profile: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        Ok(self.read()?.sections().contains(&section(profile)))
+    }
+
+    /// Moves a profile's section, and every setting in it, to a new name. A
+    /// credential_process pointing at cred-lock is pointed at the new name.
+    /// Returns whether the profile had a section
+    pub fn rename(
+        &self,
+        old: &str,
+        new: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut document = self.read()?;
+        let (old_section, new_section) = (section(old), section(new));
+        if document.sections().contains(&new_section) {
+            return Err(Failure::Duplicate(format!(
+                "{} already has a profile named {}",
+                self.path.display(),
+                new
+            ))
+            .into());
+        }
+        if !document.rename(&old_section, &new_section) {
+            return Ok(false);
+        }
+        if let Some(old_command) = document
+            .get(&new_section, "credential_process")
+            .filter(|command| command.starts_with("cred-lock "))
+        {
+            // keeps the key store the profile was configured with
+            document.set(
+                &new_section,
+                "credential_process",
+                &command(backend(&old_command), new),
+            );
+        }
+        self.write(&document)?;
+        Ok(true)
+    }
+
+    /// Removes a profile's credential_process if it points at cred-lock,
+    /// returning whether it did
+    pub fn unconfigure(
+        &self,
+        profile: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut document = self.read()?;
+        let section = section(profile);
+        match document.get(&section, "credential_process") {
+            Some(command) if command.starts_with("cred-lock ") => {
+                document.remove(&section, "credential_process");
+                self.write(&document)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// The credential_process running `cred-lock get` for a profile against a key store
+fn command(
+    backend: Option<Backend>,
+    profile: &str,
+) -> String {
+    match backend {
+        Some(backend) => format!("cred-lock --backend {} get {}", backend, quote(profile)),
+        None => format!("cred-lock get {}", quote(profile)),
+    }
+}
+
+/// The key store a cred-lock credential_process was configured with, if any
+fn backend(command: &str) -> Option<Backend> {
+    let mut words = command.split_whitespace().skip(1);
+    match words.next() {
+        Some("--backend") => words.next()?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Section of the aws cli config holding a profile's settings
+fn section(profile: &str) -> String {
+    match profile {
+        "default" => profile.into(),
+        _ => format!("profile {}", profile),
+    }
+}
+
+/// Quotes an argument for the shell-like splitting the aws cli applies to credential_process
+fn quote(argument: &str) -> String {
+    if !argument.is_empty()
+        && argument
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.@/:+".contains(c))
+    {
+        return argument.into();
+    }
+    format!(
+        "\"{}\"",
+        argument.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configures_and_unconfigures_profiles() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config");
+        AwsConfig::at(path.clone(), None).configure("dev")?;
+        AwsConfig::at(path.clone(), Some(Backend::Vault)).configure("prod ops")?;
+        assert_eq!(
+            fs::read_to_string(&path)?,
+            "[profile dev]\ncredential_process = cred-lock get dev\n\n\
+             [profile prod ops]\ncredential_process = cred-lock --backend vault get \"prod ops\"\n"
+        );
+        let aws_config = AwsConfig::at(path.clone(), None);
+        assert!(aws_config.unconfigure("dev")?);
+        assert!(!aws_config.unconfigure("dev")?);
+        assert_eq!(
+            fs::read_to_string(&path)?,
+            "[profile prod ops]\ncredential_process = cred-lock --backend vault get \"prod ops\"\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rewrites_in_place_keeping_mode_and_backend() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config");
+        fs::write(&path, "[default]\nregion = eu-west-1\n")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640))?;
+        AwsConfig::at(path.clone(), Some(Backend::Vault)).configure("dev")?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o640);
+
+        assert!(AwsConfig::at(path.clone(), None).rename("dev", "staging")?);
+        assert_eq!(
+            fs::read_to_string(&path)?,
+            "[default]\nregion = eu-west-1\n\n\
+             [profile staging]\ncredential_process = cred-lock --backend vault get staging\n"
+        );
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! cred-lock configuration, kept in `config.toml` under the user's config directory
+
+use crate::error::Failure;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env, error::Error, fs, path::PathBuf};
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    /// Role profiles keyed by profile name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roles: BTreeMap<String, Role>,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub policy: Policy,
+    /// Names to show alongside account ids, keyed by account id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub accounts: BTreeMap<String, String>,
+}
+
+/// Settings for caching temporary credentials
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheConfig {
+    /// Seconds before expiration that cached credentials are requested again
+    pub refresh_window: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            refresh_window: 300,
+        }
+    }
+}
+
+/// A profile whose credentials are obtained by assuming a role
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Role {
+    pub role_arn: String,
+    /// Profile whose credentials assume the role, either a stored profile or another role
+    pub source_profile: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role_session_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_seconds: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mfa_serial: Option<String>,
+}
+
+/// Limits on how old a stored access key may get before it should be rotated
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    /// Days after which using a key prints a warning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warn_after_days: Option<i64>,
+    /// Days after which a key is refused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age_days: Option<i64>,
+}
+
+impl Policy {
+    /// Checks the age of a profile's key, last rotated or created at `since`,
+    /// returning a warning if it is getting old and an error if it is too old
+    pub fn check(
+        &self,
+        profile: &str,
+        since: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let age = (now - since).num_days();
+        if let Some(max_age_days) = self.max_age_days {
+            if age >= max_age_days {
+                return Err(Failure::Refused(format!(
+                    "the access key for {} is {} days old, past the {} day limit. \
+                     Rotate it with `cred-lock rotate {}` or pass --ignore-key-age",
+                    profile, age, max_age_days, profile
+                ))
+                .into());
+            }
+        }
+        match self.warn_after_days {
+            Some(warn_after_days) if age >= warn_after_days => Ok(Some(format!(
+                "the access key for {} is {} days old, consider rotating it with `cred-lock rotate {}`",
+                profile, age, profile
+            ))),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Config {
+    /// Path of the config file, overridable with `CRED_LOCK_CONFIG`
+    pub fn path() -> Result<PathBuf, Box<dyn Error>> {
+        match env::var_os("CRED_LOCK_CONFIG") {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Ok(dirs::config_dir()
+                .ok_or("unable to resolve a config directory")?
+                .join("cred-lock")
+                .join("config.toml")),
+        }
+    }
+
+    /// Loads the config file, defaulting to an empty config if there isn't one
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        let path = Config::path()?;
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        toml::from_str(&fs::read_to_string(&path)?).map_err(|e| {
+            Failure::InvalidInput(format!("invalid config {}: {}", path.display(), e)).into()
+        })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = Config::path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// An account id followed by its alias, if one is configured
+    pub fn account(
+        &self,
+        account_id: &str,
+    ) -> String {
+        match self.accounts.get(account_id) {
+            Some(alias) => format!("{} ({})", account_id, alias),
+            None => account_id.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_role_profiles() -> Result<(), Box<dyn Error>> {
+        let config: Config = toml::from_str(
+            r#"
+            [roles.admin]
+            role_arn = "arn:aws:iam::123456789012:role/admin"
+            source_profile = "dev"
+            external_id = "shared"
+            duration_seconds = 900
+
+            [cache]
+            refresh_window = 600
+
+            [accounts]
+            "123456789012" = "prod"
+            "#,
+        )?;
+        assert_eq!(
+            config.roles["admin"],
+            Role {
+                role_arn: "arn:aws:iam::123456789012:role/admin".into(),
+                source_profile: "dev".into(),
+                external_id: Some("shared".into()),
+                role_session_name: None,
+                duration_seconds: Some(900),
+                mfa_serial: None,
+            }
+        );
+        assert_eq!(config.cache.refresh_window, 600);
+        assert_eq!(config.account("123456789012"), "123456789012 (prod)");
+        assert_eq!(config.account("210987654321"), "210987654321");
+        Ok(())
+    }
+
+    #[test]
+    fn policy_warns_then_refuses_old_keys() -> Result<(), Box<dyn Error>> {
+        let policy = Policy {
+            warn_after_days: Some(90),
+            max_age_days: Some(180),
+        };
+        let now = Utc::now();
+        let days = |days| now - chrono::Duration::days(days);
+        assert_eq!(policy.check("dev", days(89), now)?, None);
+        assert_eq!(
+            policy.check("dev", days(90), now)?.as_deref(),
+            Some("the access key for dev is 90 days old, consider rotating it with `cred-lock rotate dev`")
+        );
+        assert!(policy.check("dev", days(180), now).is_err());
+        assert_eq!(Policy::default().check("dev", days(1000), now)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn refusing_old_keys_has_its_own_exit_code() -> Result<(), Box<dyn Error>> {
+        let policy = Policy {
+            warn_after_days: None,
+            max_age_days: Some(90),
+        };
+        let now = Utc::now();
+        let error = policy
+            .check("dev", now - chrono::Duration::days(90), now)
+            .err()
+            .ok_or("expected an error")?;
+        assert_eq!(crate::error::exit_code(error.as_ref()), 9);
+        Ok(())
+    }
+}
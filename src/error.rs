@@ -0,0 +1,163 @@
+//! Failures callers need to tell apart, each exiting with its own status
+//!
+//! | status | failure                                              |
+//! |--------|------------------------------------------------------|
+//! | 1      | anything else, such as a network or filesystem error |
+//! | 3      | nothing is stored or configured under a name         |
+//! | 4      | the key store is locked                              |
+//! | 5      | the user cancelled a prompt                          |
+//! | 6      | something is already stored under a name             |
+//! | 7      | the key store failed or holds corrupted data         |
+//! | 8      | input was malformed or inconsistent                  |
+//! | 9      | policy refused the request, such as an over-age key  |
+//!
+//! Messages are written by cred-lock itself and never include secret material.
+
+use std::{error::Error, fmt, io};
+
+/// Exit status for failures not covered by a [`Failure`]
+pub const EXIT_OTHER: i32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    NotFound(String),
+    Locked(String),
+    UserCancelled(String),
+    Duplicate(String),
+    Backend(String),
+    InvalidInput(String),
+    Refused(String),
+}
+
+impl Failure {
+    /// Process exit status reported for this failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::NotFound(_) => 3,
+            Failure::Locked(_) => 4,
+            Failure::UserCancelled(_) => 5,
+            Failure::Duplicate(_) => 6,
+            Failure::Backend(_) => 7,
+            Failure::InvalidInput(_) => 8,
+            Failure::Refused(_) => 9,
+        }
+    }
+
+    /// Classifies a failed interactive prompt, treating end of input or an
+    /// interrupt as the user cancelling
+    pub fn prompt(error: io::Error) -> Box<dyn Error> {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::Interrupted => {
+                Failure::UserCancelled("prompt was cancelled".into()).into()
+            }
+            _ => error.into(),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self {
+            Failure::NotFound(message)
+            | Failure::Locked(message)
+            | Failure::UserCancelled(message)
+            | Failure::Duplicate(message)
+            | Failure::Backend(message)
+            | Failure::InvalidInput(message)
+            | Failure::Refused(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Error for Failure {}
+
+/// Rewords an error, keeping the kind of failure it is and so its exit status
+pub fn reword(
+    error: &(dyn Error + 'static),
+    message: String,
+) -> Box<dyn Error> {
+    match error.downcast_ref::<Failure>() {
+        Some(Failure::NotFound(_)) => Failure::NotFound(message).into(),
+        Some(Failure::Locked(_)) => Failure::Locked(message).into(),
+        Some(Failure::UserCancelled(_)) => Failure::UserCancelled(message).into(),
+        Some(Failure::Duplicate(_)) => Failure::Duplicate(message).into(),
+        Some(Failure::Backend(_)) => Failure::Backend(message).into(),
+        Some(Failure::InvalidInput(_)) => Failure::InvalidInput(message).into(),
+        Some(Failure::Refused(_)) => Failure::Refused(message).into(),
+        None => message.into(),
+    }
+}
+
+/// Exit status for any error, falling back to [`EXIT_OTHER`] for unclassified ones
+pub fn exit_code(error: &(dyn Error + 'static)) -> i32 {
+    error
+        .downcast_ref::<Failure>()
+        .map_or(EXIT_OTHER, Failure::exit_code)
+}
+
+/// Whether an error is a particular kind of failure
+#[cfg(test)]
+pub fn is(
+    error: &(dyn Error + 'static),
+    kind: fn(String) -> Failure,
+) -> bool {
+    error.downcast_ref::<Failure>().map(std::mem::discriminant)
+        == Some(std::mem::discriminant(&kind(String::new())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_failures_to_distinct_exit_codes() {
+        let failures = [
+            Failure::NotFound("".into()),
+            Failure::Locked("".into()),
+            Failure::UserCancelled("".into()),
+            Failure::Duplicate("".into()),
+            Failure::Backend("".into()),
+            Failure::InvalidInput("".into()),
+            Failure::Refused("".into()),
+        ];
+        let mut codes: Vec<i32> = failures.iter().map(Failure::exit_code).collect();
+        codes.push(EXIT_OTHER);
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), failures.len() + 1);
+
+        let boxed: Box<dyn Error> = Failure::Locked("key store is locked".into()).into();
+        assert_eq!(exit_code(boxed.as_ref()), 4);
+        assert!(is(boxed.as_ref(), Failure::Locked));
+        assert!(!is(boxed.as_ref(), Failure::NotFound));
+        let other: Box<dyn Error> = "network unreachable".into();
+        assert_eq!(exit_code(other.as_ref()), EXIT_OTHER);
+    }
+
+    #[test]
+    fn rewording_keeps_exit_codes() {
+        let locked: Box<dyn Error> = Failure::Locked("key store is locked".into()).into();
+        let reworded = reword(
+            locked.as_ref(),
+            "rotation failed: key store is locked".into(),
+        );
+        assert_eq!(reworded.to_string(), "rotation failed: key store is locked");
+        assert_eq!(exit_code(reworded.as_ref()), 4);
+        let other: Box<dyn Error> = "network unreachable".into();
+        assert_eq!(
+            exit_code(reword(other.as_ref(), "rotation failed".into()).as_ref()),
+            EXIT_OTHER
+        );
+    }
+
+    #[test]
+    fn treats_interrupted_prompts_as_cancelled() {
+        let cancelled = Failure::prompt(io::ErrorKind::UnexpectedEof.into());
+        assert_eq!(exit_code(cancelled.as_ref()), 5);
+        let failed = Failure::prompt(io::ErrorKind::PermissionDenied.into());
+        assert_eq!(exit_code(failed.as_ref()), EXIT_OTHER);
+    }
+}
@@ -0,0 +1,296 @@
+//! Line preserving editor for the INI files the aws cli reads
+//! <https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-files.html>
+//!
+//! Only the lines being changed are touched, so comments, blank lines and
+//! the order of sections and settings survive edits.
+
+use std::fmt;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Document {
+    lines: Vec<String>,
+}
+
+/// Name of a section header line, with runs of whitespace collapsed
+fn header(line: &str) -> Option<String> {
+    let line = line.trim();
+    if line.starts_with('[') && line.ends_with(']') {
+        Some(
+            line[1..line.len() - 1]
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    } else {
+        None
+    }
+}
+
+/// Name and value of a top level setting line
+fn setting(line: &str) -> Option<(&str, &str)> {
+    if line.starts_with(char::is_whitespace) || line.starts_with('#') || line.starts_with(';') {
+        return None;
+    }
+    line.split_once('=')
+        .map(|(name, value)| (name.trim(), value.trim()))
+}
+
+/// Continuation lines are indented under the setting they belong to
+fn continuation(line: &str) -> bool {
+    line.starts_with(char::is_whitespace) && !line.trim().is_empty()
+}
+
+impl Document {
+    pub fn parse(text: &str) -> Self {
+        Document {
+            lines: text.lines().map(String::from).collect(),
+        }
+    }
+
+    /// Section names in the order they appear
+    pub fn sections(&self) -> Vec<String> {
+        self.lines.iter().filter_map(|line| header(line)).collect()
+    }
+
+    /// Top level settings of a section in the order they appear
+    pub fn settings(
+        &self,
+        section: &str,
+    ) -> Vec<(String, String)> {
+        match self.section(section) {
+            Some((start, end)) => self.lines[start + 1..end]
+                .iter()
+                .filter_map(|line| setting(line))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get(
+        &self,
+        section: &str,
+        name: &str,
+    ) -> Option<String> {
+        self.settings(section)
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Sets a value, adding the setting to the end of its section or the
+    /// section to the end of the document if either doesn't exist yet
+    pub fn set(
+        &mut self,
+        section: &str,
+        name: &str,
+        value: &str,
+    ) {
+        let line = format!("{} = {}", name, value);
+        let (start, end) = match self.section(section) {
+            Some(range) => range,
+            None => {
+                if self
+                    .lines
+                    .last()
+                    .is_some_and(|last| !last.trim().is_empty())
+                {
+                    self.lines.push(String::new());
+                }
+                self.lines.push(format!("[{}]", section));
+                self.lines.push(line);
+                return;
+            }
+        };
+        if let Some(index) = self.find(start, end, name) {
+            self.lines[index] = line;
+            return;
+        }
+        let last = (start..end)
+            .rev()
+            .find(|&index| !self.lines[index].trim().is_empty())
+            .unwrap_or(start);
+        self.lines.insert(last + 1, line);
+    }
+
+    /// Removes a setting along with its continuation lines, then its section
+    /// if nothing but blank lines remain in it. Returns whether it was present
+    pub fn remove(
+        &mut self,
+        section: &str,
+        name: &str,
+    ) -> bool {
+        let (start, end) = match self.section(section) {
+            Some(range) => range,
+            None => return false,
+        };
+        let index = match self.find(start, end, name) {
+            Some(index) => index,
+            None => return false,
+        };
+        let mut until = index + 1;
+        while until < end && continuation(&self.lines[until]) {
+            until += 1;
+        }
+        self.lines.drain(index..until);
+        let end = end - (until - index);
+        if self.lines[start + 1..end]
+            .iter()
+            .all(|line| line.trim().is_empty())
+        {
+            self.lines.drain(start..end);
+            if start == self.lines.len() {
+                while self.lines.last().is_some_and(|last| last.trim().is_empty()) {
+                    self.lines.pop();
+                }
+            }
+        }
+        true
+    }
+
+    /// Renames a section, keeping everything in it. Returns whether it was present
+    pub fn rename(
+        &mut self,
+        section: &str,
+        name: &str,
+    ) -> bool {
+        match self.section(section) {
+            Some((start, _)) => {
+                self.lines[start] = format!("[{}]", name);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Line range of a section, from its header up to the next one
+    fn section(
+        &self,
+        name: &str,
+    ) -> Option<(usize, usize)> {
+        let start = self
+            .lines
+            .iter()
+            .position(|line| header(line).as_deref() == Some(name))?;
+        let end = self.lines[start + 1..]
+            .iter()
+            .position(|line| header(line).is_some())
+            .map_or(self.lines.len(), |offset| start + 1 + offset);
+        Some((start, end))
+    }
+
+    fn find(
+        &self,
+        start: usize,
+        end: usize,
+        name: &str,
+    ) -> Option<usize> {
+        (start + 1..end)
+            .find(|&index| setting(&self.lines[index]).is_some_and(|(key, _)| key == name))
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "# managed by hand
+[default]
+region = us-east-1
+
+[profile dev]
+# development account
+region = eu-west-1
+s3 =
+  max_concurrent_requests = 20
+credential_process = aws-vault exec dev
+
+[profile  ops]
+output = json
+";
+
+    #[test]
+    fn edits_settings_preserving_everything_else() {
+        let mut document = Document::parse(CONFIG);
+        assert_eq!(
+            document.sections(),
+            vec!["default", "profile dev", "profile ops"]
+        );
+        document.set("profile dev", "credential_process", "cred-lock get dev");
+        document.set("profile ops", "credential_process", "cred-lock get ops");
+        document.set("profile new", "credential_process", "cred-lock get new");
+        assert_eq!(
+            document.get("profile ops", "credential_process").as_deref(),
+            Some("cred-lock get ops")
+        );
+        assert_eq!(
+            document.to_string(),
+            "# managed by hand
+[default]
+region = us-east-1
+
+[profile dev]
+# development account
+region = eu-west-1
+s3 =
+  max_concurrent_requests = 20
+credential_process = cred-lock get dev
+
+[profile  ops]
+output = json
+credential_process = cred-lock get ops
+
+[profile new]
+credential_process = cred-lock get new
+"
+        );
+    }
+
+    #[test]
+    fn removes_settings_and_emptied_sections() {
+        let mut document = Document::parse(CONFIG);
+        assert!(document.remove("profile dev", "s3"));
+        assert!(document.remove("profile ops", "output"));
+        assert!(!document.remove("profile ops", "output"));
+        assert!(!document.remove("profile missing", "output"));
+        assert_eq!(
+            document.to_string(),
+            "# managed by hand
+[default]
+region = us-east-1
+
+[profile dev]
+# development account
+region = eu-west-1
+credential_process = aws-vault exec dev
+"
+        );
+    }
+
+    #[test]
+    fn renames_sections_with_their_settings() {
+        let mut document = Document::parse(CONFIG);
+        assert!(document.rename("profile ops", "profile infra"));
+        assert!(!document.rename("profile missing", "profile other"));
+        assert_eq!(
+            document.sections(),
+            vec!["default", "profile dev", "profile infra"]
+        );
+        assert_eq!(
+            document.get("profile infra", "output").as_deref(),
+            Some("json")
+        );
+    }
+}
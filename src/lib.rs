@@ -0,0 +1,95 @@
+//! Keeps long-term AWS credentials in an OS key store rather than in plain
+//! text, handing them out, or temporary credentials derived from them, to
+//! whatever asks for a profile.
+//!
+//! The `cred-lock` cli is a thin front end over this library, which can be
+//! used to read profiles without shelling out to `cred-lock get`:
+//!
+//! ```no_run
+//! use cred_lock::{cache::Cache, config::Config, metadata::MetadataFile, profile, store};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let config = Config::load()?;
+//! let mut store = store::open(None)?;
+//! let credentials = profile::resolve(
+//!     store.as_mut(),
+//!     &config,
+//!     &Cache::new(config.cache.refresh_window)?,
+//!     &MetadataFile::new()?,
+//!     profile::Request::new("dev"),
+//!     &mut |serial_number| Err(format!("no MFA code for {}", serial_number).into()),
+//! )?;
+//! println!("{}", credentials.access_key_id);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Errors are boxed, and those callers may want to act on are an
+//! [`error::Failure`] underneath.
+
+#![deny(warnings)]
+
+pub mod access_key;
+pub mod agent;
+mod atomic;
+mod aws;
+pub mod aws_cli;
+pub mod cache;
+pub mod config;
+mod csv;
+pub mod error;
+pub mod export;
+mod iam;
+mod ini;
+pub mod listing;
+pub mod manage;
+pub mod metadata;
+pub mod profile;
+pub mod rotate;
+pub mod secret;
+pub mod store;
+mod sts;
+
+pub use aws::Endpoint;
+pub use secret::Secret;
+pub use sts::Identity;
+
+use serde::{Deserialize, Serialize};
+
+/// Credentials Process representation of AWS credentials
+/// <https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-sourcing-external.html>
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Credentials {
+    /// Always 1
+    pub version: u8,
+    pub access_key_id: String,
+    pub secret_access_key: Secret,
+    /// Set on temporary credentials only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<Secret>,
+    /// RFC 3339 time temporary credentials expire at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn credentials_serialize_as_expected() -> Result<(), Box<dyn Error>> {
+        assert_eq!(
+            serde_json::to_string(&Credentials {
+                version: 1,
+                access_key_id: "key".into(),
+                secret_access_key: "secret".into(),
+                session_token: None,
+                expiration: None,
+            })?,
+            r#"{"Version":1,"AccessKeyId":"key","SecretAccessKey":"secret"}"#
+        );
+        Ok(())
+    }
+}
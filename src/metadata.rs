@@ -0,0 +1,182 @@
+//! Per-profile metadata, kept in a json file alongside the key store
+//!
+//! Key stores only hold a profile's name, access key id and secret, so
+//! when keys were created, used and rotated along with descriptive
+//! details are recorded separately. None of it is secret.
+
+use crate::atomic;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::DirBuilderExt,
+    path::PathBuf,
+};
+
+/// What cred-lock knows about a profile beyond its credentials
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_rotated_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// The file metadata for every profile is kept in
+pub struct MetadataFile {
+    path: PathBuf,
+}
+
+impl MetadataFile {
+    /// Opens the metadata file at `CRED_LOCK_METADATA`, defaulting to the user's data directory
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let path = match env::var_os("CRED_LOCK_METADATA") {
+            Some(path) => PathBuf::from(path),
+            None => dirs::data_dir()
+                .ok_or("unable to resolve a data directory for profile metadata")?
+                .join("cred-lock")
+                .join("metadata.json"),
+        };
+        Ok(MetadataFile::at(path))
+    }
+
+    pub fn at(path: PathBuf) -> Self {
+        MetadataFile { path }
+    }
+
+    /// Metadata of every profile, by profile name. Only a missing file counts as
+    /// empty, so one that can't be read is never overwritten with nothing
+    pub fn load(&self) -> Result<BTreeMap<String, Metadata>, Box<dyn Error>> {
+        match fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| format!("invalid metadata {}: {}", self.path.display(), e).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(format!("unable to read metadata {}: {}", self.path.display(), e).into()),
+        }
+    }
+
+    pub fn get(
+        &self,
+        profile: &str,
+    ) -> Result<Metadata, Box<dyn Error>> {
+        Ok(self.load()?.remove(profile).unwrap_or_default())
+    }
+
+    /// Applies a change to a profile's metadata
+    pub fn update(
+        &self,
+        profile: &str,
+        change: impl FnOnce(&mut Metadata),
+    ) -> Result<(), Box<dyn Error>> {
+        let _lock = self.lock()?;
+        let mut all = self.load()?;
+        change(all.entry(profile.into()).or_default());
+        self.save(&all)
+    }
+
+    pub fn remove(
+        &self,
+        profile: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let _lock = self.lock()?;
+        let mut all = self.load()?;
+        if all.remove(profile).is_some() {
+            self.save(&all)?;
+        }
+        Ok(())
+    }
+
+    /// Moves a profile's metadata to a new name
+    pub fn rename(
+        &self,
+        old: &str,
+        new: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let _lock = self.lock()?;
+        let mut all = self.load()?;
+        if let Some(details) = all.remove(old) {
+            all.insert(new.into(), details);
+            self.save(&all)?;
+        }
+        Ok(())
+    }
+
+    /// Holds off other processes changing metadata until dropped
+    fn lock(&self) -> Result<atomic::Lock, Box<dyn Error>> {
+        if let Some(parent) = self.path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+        Ok(atomic::Lock::exclusive(&self.path)?)
+    }
+
+    fn save(
+        &self,
+        all: &BTreeMap<String, Metadata>,
+    ) -> Result<(), Box<dyn Error>> {
+        atomic::write(&self.path, &serde_json::to_vec_pretty(all)?, 0o600)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn concurrent_updates_are_all_kept() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("metadata.json");
+        let updates = (0..8)
+            .map(|i| {
+                let metadata = MetadataFile::at(path.clone());
+                thread::spawn(move || {
+                    metadata
+                        .update(&format!("profile{}", i), |details| {
+                            details.last_used_at = Some(Utc::now())
+                        })
+                        .map_err(|e| e.to_string())
+                })
+            })
+            .collect::<Vec<_>>();
+        for update in updates {
+            update.join().unwrap()?;
+        }
+        let all = MetadataFile::at(path).load()?;
+        assert_eq!(all.len(), 8);
+        assert!(all.values().all(|details| details.last_used_at.is_some()));
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_metadata_is_never_overwritten() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("metadata.json");
+        let metadata = MetadataFile::at(path.clone());
+        assert!(metadata.load()?.is_empty());
+
+        fs::write(&path, "{\"dev\": {\"owner\": ")?;
+        assert!(metadata.update("prod", |_| ()).is_err());
+        assert_eq!(fs::read_to_string(&path)?, "{\"dev\": {\"owner\": ");
+
+        fs::remove_file(&path)?;
+        fs::create_dir(&path)?;
+        assert!(metadata.load().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! Secret values, such as secret access keys and session tokens, kept out of
+//! logs, swap and freed memory
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use zeroize::Zeroize;
+
+/// A secret string. Its buffer is locked into memory where the OS allows it,
+/// overwritten with zeros when dropped and redacted when debugged or displayed.
+/// Serializing it is the one way it is written out in full
+pub struct Secret(String);
+
+impl Secret {
+    /// Takes ownership of a secret, locking its buffer in place. The buffer
+    /// is never grown, so it never moves while held
+    pub fn new(value: String) -> Self {
+        lock(value.as_ptr(), value.capacity(), true);
+        Secret(value)
+    }
+
+    /// The secret itself, for handing to what consumes it such as a key
+    /// store or request signer. Avoid copying it into owned strings
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret::new(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret::new(value.into())
+    }
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Self {
+        Secret::new(self.0.clone())
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(
+        &self,
+        other: &Self,
+    ) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        f.write_str("Secret([redacted])")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        wipe(&mut self.0, |address, length| lock(address, length, false));
+    }
+}
+
+/// Zeroes a string's whole allocation, not just its length, then hands the
+/// allocation to `unlock`. Zeroing empties the string, so it is noted first
+fn wipe(
+    value: &mut String,
+    unlock: impl FnOnce(*const u8, usize),
+) {
+    let (address, capacity) = (value.as_ptr(), value.capacity());
+    value.zeroize();
+    unlock(address, capacity);
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+/// Locks or unlocks an allocation in memory so it is never swapped to disk.
+/// This is best effort: locking fails quietly past the process's RLIMIT_MEMLOCK, and
+/// as locks cover whole pages, unlocking one secret can unlock a neighbour sharing its page
+#[cfg(unix)]
+fn lock(
+    address: *const u8,
+    length: usize,
+    locked: bool,
+) {
+    if length == 0 {
+        return;
+    }
+    // safety: the range is a live allocation. Failing to lock or unlock is tolerated, as above
+    unsafe {
+        if locked {
+            libc::mlock(address as *const libc::c_void, length);
+        } else {
+            libc::munlock(address as *const libc::c_void, length);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(
+    _: *const u8,
+    _: usize,
+    _: bool,
+) {
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn unlocks_the_whole_allocation_once_zeroed() {
+        let mut value = String::with_capacity(64);
+        value.push_str("wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY");
+        let address = value.as_ptr();
+        let mut unlocked = None;
+        wipe(&mut value, |address, length| {
+            // safety: the allocation is still owned by `value`
+            let bytes = unsafe { std::slice::from_raw_parts(address, length) };
+            unlocked = Some((address, length, bytes.iter().all(|byte| *byte == 0)));
+        });
+        assert_eq!(unlocked, Some((address, 64, true)));
+    }
+
+    #[test]
+    fn redacts_all_but_serialization() -> Result<(), Box<dyn Error>> {
+        let secret = Secret::from("wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY");
+        assert_eq!(format!("{}", secret), "[redacted]");
+        assert_eq!(format!("{:?}", Some(&secret)), "Some(Secret([redacted]))");
+        assert_eq!(
+            serde_json::to_string(&secret)?,
+            r#""wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY""#
+        );
+        let parsed: Secret = serde_json::from_str(r#""token""#)?;
+        assert_eq!(parsed.expose(), "token");
+        assert_eq!(parsed.clone(), parsed);
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{error::Failure, secret::Secret};
+use std::{error::Error, fmt, str::FromStr};
+
+#[cfg(target_os = "macos")]
+mod keychain;
+#[cfg(target_os = "linux")]
+mod keyutils;
+pub mod memory;
+#[cfg(target_os = "linux")]
+mod secret_service;
+#[cfg(target_os = "linux")]
+mod vault;
+
+/// Name of the dedicated key store cred-lock keeps credentials in
+pub const DEFAULT_CHAIN: &str = "aws-credlock";
+
+/// Seconds a key store may sit idle before it locks itself
+pub const LOCK_INTERVAL: u32 = 300;
+
+/// A set of long-term AWS credentials stored under a profile name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub profile: String,
+    pub access_key_id: String,
+    pub secret_access_key: Secret,
+}
+
+/// A stored profile and its access key id, listed without the secret
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub profile: String,
+    pub access_key_id: String,
+}
+
+impl Listing {
+    /// Whether this lists the given credentials
+    pub fn lists(
+        &self,
+        profile: &str,
+        access_key_id: &str,
+    ) -> bool {
+        self.profile == profile && self.access_key_id == access_key_id
+    }
+
+    fn missing(&self) -> Box<dyn Error> {
+        Failure::NotFound(format!(
+            "no credentials stored for profile {} with access key {}",
+            self.profile, self.access_key_id
+        ))
+        .into()
+    }
+}
+
+/// Operations cred-lock needs from a backing key store
+pub trait CredentialStore {
+    /// Creates the key store, configured to lock itself when idle
+    fn init(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Stores a set of credentials
+    fn put(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Fetches every set of credentials stored for a profile
+    fn fetch(
+        &mut self,
+        profile: &str,
+    ) -> Result<Vec<Entry>, Box<dyn Error>>;
+
+    /// Deletes every set of credentials stored for a profile
+    fn delete(
+        &mut self,
+        profile: &str,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Replaces a stored set of credentials with another, possibly under a different
+    /// profile. Should it fail part way, one set or the other remains stored
+    fn replace(
+        &mut self,
+        old: &Listing,
+        new: Entry,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Lists every profile and access key id held in the key store
+    fn list(&mut self) -> Result<Vec<Listing>, Box<dyn Error>>;
+
+    /// Locks the key store
+    fn lock(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Unlocks the key store, prompting the user if needed
+    fn unlock(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+/// Key store implementations credentials may be kept in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// macOS keychain
+    Keychain,
+    /// Passphrase protected vault file
+    Vault,
+    /// Linux kernel keyring
+    Keyutils,
+    /// freedesktop Secret Service provider such as GNOME Keyring or KWallet
+    SecretService,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        if cfg!(target_os = "macos") {
+            Backend::Keychain
+        } else {
+            Backend::Vault
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keychain" => Ok(Backend::Keychain),
+            "vault" => Ok(Backend::Vault),
+            "keyutils" => Ok(Backend::Keyutils),
+            "secret-service" => Ok(Backend::SecretService),
+            other => Err(format!(
+                "unknown key store {}, expected one of keychain, vault, keyutils or secret-service",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Keychain => "keychain",
+            Backend::Vault => "vault",
+            Backend::Keyutils => "keyutils",
+            Backend::SecretService => "secret-service",
+        })
+    }
+}
+
+/// Opens a key store, defaulting to the one native to this platform
+pub fn open(backend: Option<Backend>) -> Result<Box<dyn CredentialStore>, Box<dyn Error>> {
+    let store: Box<dyn CredentialStore> = match backend.unwrap_or_default() {
+        #[cfg(target_os = "macos")]
+        Backend::Keychain => Box::new(keychain::Keychain::new(DEFAULT_CHAIN)),
+        #[cfg(target_os = "linux")]
+        Backend::Vault => Box::new(vault::Vault::new().map_err(backend_failure)?),
+        #[cfg(target_os = "linux")]
+        Backend::Keyutils => Box::new(keyutils::KernelKeyring::new(DEFAULT_CHAIN)),
+        #[cfg(target_os = "linux")]
+        Backend::SecretService => Box::new(secret_service::SecretService::new(DEFAULT_CHAIN)),
+        #[allow(unreachable_patterns)]
+        other => {
+            return Err(Failure::Backend(format!(
+                "the {} key store is not available on this platform",
+                other
+            ))
+            .into())
+        }
+    };
+    Ok(Box::new(Classified(store)))
+}
+
+/// Reports errors a key store didn't classify itself as backend failures
+fn backend_failure(error: Box<dyn Error>) -> Box<dyn Error> {
+    if error.is::<Failure>() {
+        error
+    } else {
+        Failure::Backend(error.to_string()).into()
+    }
+}
+
+/// A key store whose failures are all classified
+struct Classified(Box<dyn CredentialStore>);
+
+impl CredentialStore for Classified {
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        self.0.init().map_err(backend_failure)
+    }
+
+    fn put(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        self.0.put(entry).map_err(backend_failure)
+    }
+
+    fn fetch(
+        &mut self,
+        profile: &str,
+    ) -> Result<Vec<Entry>, Box<dyn Error>> {
+        self.0.fetch(profile).map_err(backend_failure)
+    }
+
+    fn delete(
+        &mut self,
+        profile: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.0.delete(profile).map_err(backend_failure)
+    }
+
+    fn replace(
+        &mut self,
+        old: &Listing,
+        new: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        self.0.replace(old, new).map_err(backend_failure)
+    }
+
+    fn list(&mut self) -> Result<Vec<Listing>, Box<dyn Error>> {
+        self.0.list().map_err(backend_failure)
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn Error>> {
+        self.0.lock().map_err(backend_failure)
+    }
+
+    fn unlock(&mut self) -> Result<(), Box<dyn Error>> {
+        self.0.unlock().map_err(backend_failure)
+    }
+}
@@ -0,0 +1,197 @@
+//! macOS keychain backed key store
+
+use super::{CredentialStore, Entry, Listing, LOCK_INTERVAL};
+use crate::error::Failure;
+use core_foundation::base::{OSStatus, TCFType};
+use security_framework::{
+    base,
+    item::{ItemClass, ItemSearchOptions},
+    os::macos::keychain::{CreateOptions, KeychainSettings, SecKeychain},
+};
+use security_framework_sys::base::{errSecSuccess, SecKeychainRef};
+use std::error::Error;
+
+extern "C" {
+    // not exposed by security-framework-sys
+    fn SecKeychainLock(keychain: SecKeychainRef) -> OSStatus;
+}
+
+// result codes from SecBase.h callers can act on
+const ERR_SEC_USER_CANCELED: OSStatus = -128;
+const ERR_SEC_AUTH_FAILED: OSStatus = -25293;
+const ERR_SEC_NO_SUCH_KEYCHAIN: OSStatus = -25294;
+const ERR_SEC_DUPLICATE_KEYCHAIN: OSStatus = -25296;
+const ERR_SEC_DUPLICATE_ITEM: OSStatus = -25299;
+const ERR_SEC_ITEM_NOT_FOUND: OSStatus = -25300;
+const ERR_SEC_INTERACTION_NOT_ALLOWED: OSStatus = -25308;
+
+/// Classifies a security framework error by its result code
+fn failure(error: base::Error) -> Box<dyn Error> {
+    let message = error.to_string();
+    match error.code() {
+        ERR_SEC_USER_CANCELED => Failure::UserCancelled(message),
+        ERR_SEC_AUTH_FAILED | ERR_SEC_INTERACTION_NOT_ALLOWED => Failure::Locked(message),
+        ERR_SEC_NO_SUCH_KEYCHAIN | ERR_SEC_ITEM_NOT_FOUND => Failure::NotFound(message),
+        ERR_SEC_DUPLICATE_KEYCHAIN | ERR_SEC_DUPLICATE_ITEM => Failure::Duplicate(message),
+        _ => Failure::Backend(message),
+    }
+    .into()
+}
+
+pub struct Keychain {
+    name: String,
+}
+
+impl Keychain {
+    pub fn new(name: &str) -> Self {
+        Keychain { name: name.into() }
+    }
+
+    fn open(&self) -> Result<SecKeychain, Box<dyn Error>> {
+        SecKeychain::open(&self.name).map_err(failure)
+    }
+}
+
+impl CredentialStore for Keychain {
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut chain = CreateOptions::new()
+            .prompt_user(true)
+            .create(&self.name)
+            .map_err(failure)?;
+        let mut settings = KeychainSettings::new();
+        settings.set_lock_on_sleep(true);
+        settings.set_lock_interval(Some(LOCK_INTERVAL));
+        chain.set_settings(&settings).map_err(failure)?;
+        Ok(())
+    }
+
+    fn put(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        self.open()?
+            .add_generic_password(
+                entry.profile.as_str(),
+                entry.access_key_id.as_str(),
+                entry.secret_access_key.expose().as_bytes(),
+            )
+            .map_err(failure)?;
+        Ok(())
+    }
+
+    fn fetch(
+        &mut self,
+        profile: &str,
+    ) -> Result<Vec<Entry>, Box<dyn Error>> {
+        Ok(ItemSearchOptions::new()
+            .keychains(&[self.open()?])
+            .class(ItemClass::generic_password())
+            .label(profile)
+            .load_data(true)
+            .load_attributes(true)
+            .search()
+            .map_err(failure)?
+            .into_iter()
+            .map(|item| {
+                let mut attributes = item.simplify_dict().unwrap_or_default();
+                Entry {
+                    profile: profile.into(),
+                    access_key_id: attributes.remove("acct").unwrap_or_default(),
+                    // taken rather than copied, so the only copy is zeroed on drop
+                    secret_access_key: attributes.remove("v_Data").unwrap_or_default().into(),
+                }
+            })
+            .collect())
+    }
+
+    fn delete(
+        &mut self,
+        profile: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let chain = self.open()?;
+        for item in ItemSearchOptions::new()
+            .keychains(&[chain.clone()])
+            .class(ItemClass::generic_password())
+            .label(profile)
+            .load_attributes(true)
+            .search()
+            .map_err(failure)?
+        {
+            let attributes = item.simplify_dict().unwrap_or_default();
+            let access_key_id = attributes.get("acct").cloned().unwrap_or_default();
+            let (_, item) = chain
+                .find_generic_password(profile, &access_key_id)
+                .map_err(failure)?;
+            item.delete();
+        }
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        old: &Listing,
+        new: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        let chain = self.open()?;
+        let (_, item) = chain
+            .find_generic_password(&old.profile, &old.access_key_id)
+            .map_err(|_| old.missing())?;
+        if old.lists(&new.profile, &new.access_key_id) {
+            // updates the existing item's secret in place
+            chain
+                .set_generic_password(
+                    &new.profile,
+                    &new.access_key_id,
+                    new.secret_access_key.expose().as_bytes(),
+                )
+                .map_err(failure)?;
+        } else {
+            // the new item is added before the old one goes, so a failure
+            // leaves at least one of them stored
+            chain
+                .add_generic_password(
+                    &new.profile,
+                    &new.access_key_id,
+                    new.secret_access_key.expose().as_bytes(),
+                )
+                .map_err(failure)?;
+            item.delete();
+        }
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<Vec<Listing>, Box<dyn Error>> {
+        // keychain searches can't be paged, and default to a single match,
+        // so ask for every item. Only attributes are loaded, leaving secrets
+        // encrypted
+        Ok(ItemSearchOptions::new()
+            .keychains(&[self.open()?])
+            .class(ItemClass::generic_password())
+            .limit(i64::from(i32::MAX))
+            .load_attributes(true)
+            .search()
+            .map_err(failure)?
+            .into_iter()
+            .filter_map(|result| {
+                let mut attributes = result.simplify_dict().unwrap_or_default();
+                Some(Listing {
+                    profile: attributes.remove("labl")?,
+                    access_key_id: attributes.remove("acct").unwrap_or_default(),
+                })
+            })
+            .collect())
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn Error>> {
+        let chain = self.open()?;
+        match unsafe { SecKeychainLock(chain.as_concrete_TypeRef()) } {
+            status if status == errSecSuccess => Ok(()),
+            status => Err(failure(base::Error::from_code(status))),
+        }
+    }
+
+    fn unlock(&mut self) -> Result<(), Box<dyn Error>> {
+        self.open()?.unlock(None).map_err(failure)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,481 @@
+//! Linux kernel keyring backed key store
+//!
+//! Credentials live in a dedicated keyring linked into the user keyring.
+//! Each profile is a `user` key described as `<profile>:<access_key_id>`
+//! whose payload is the secret access key. Stored keys never expire.
+//!
+//! Unlocking adds a marker key to the user keyring, beside the credentials
+//! keyring, with a timeout of the lock interval, renewed whenever credentials
+//! are read, so a store left idle locks itself once the kernel discards the
+//! marker. Locking withholds read and search permission on every key until
+//! the keyring is unlocked again. Like the keys, the marker is shared by all
+//! of the user's login sessions, so the store is locked and unlocked for all
+//! of them at once.
+//!
+//! Locking here is advisory only. Nothing gates unlocking, so any process
+//! running as the same user can unlock the keyring, or restore permissions
+//! on its keys, without authenticating.
+
+use super::{CredentialStore, Entry, Listing, LOCK_INTERVAL};
+use crate::error::Failure;
+use libc::{c_long, c_ulong};
+use std::{error::Error, ffi::CString, io, ptr};
+
+type KeySerial = i32;
+type StoredKey = (KeySerial, String, String);
+
+const KEY_SPEC_USER_KEYRING: KeySerial = -4;
+
+const KEYCTL_SETPERM: c_long = 5;
+const KEYCTL_DESCRIBE: c_long = 6;
+const KEYCTL_SEARCH: c_long = 10;
+const KEYCTL_READ: c_long = 11;
+const KEYCTL_SET_TIMEOUT: c_long = 15;
+const KEYCTL_INVALIDATE: c_long = 21;
+
+/// possessor: all, user: view (the kernel's default)
+const UNLOCKED: u32 = 0x3f01_0000;
+/// possessor: view, write, link, setattr, user: view, setattr
+///
+/// Without search permission a key is no longer possessed, so the user
+/// keeps setattr to be able to unlock it again
+const LOCKED: u32 = 0x3521_0000;
+
+pub struct KernelKeyring {
+    name: String,
+    /// Keyring the credentials keyring and the marker of an unlocked store are linked into
+    parent: KeySerial,
+    /// Seconds the store stays unlocked without credentials being read
+    lock_interval: u32,
+}
+
+impl KernelKeyring {
+    pub fn new(name: &str) -> Self {
+        KernelKeyring {
+            name: name.into(),
+            parent: KEY_SPEC_USER_KEYRING,
+            lock_interval: LOCK_INTERVAL,
+        }
+    }
+
+    fn marker(&self) -> String {
+        format!("{}:unlocked", self.name)
+    }
+
+    /// Finds the marker of an unlocked store, if it hasn't expired
+    fn unlocked(&self) -> Result<Option<KeySerial>, Box<dyn Error>> {
+        let kind = CString::new("user")?;
+        let marker = CString::new(self.marker())?;
+        match keyctl(
+            KEYCTL_SEARCH,
+            self.parent as c_ulong,
+            kind.as_ptr() as c_ulong,
+            marker.as_ptr() as c_ulong,
+            0,
+        ) {
+            Ok(serial) => Ok(Some(serial as KeySerial)),
+            Err(e) if gone(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn find(&self) -> Result<KeySerial, Box<dyn Error>> {
+        let name = CString::new(self.name.as_str())?;
+        match keyctl(
+            KEYCTL_SEARCH,
+            self.parent as c_ulong,
+            b"keyring\0".as_ptr() as c_ulong,
+            name.as_ptr() as c_ulong,
+            0,
+        ) {
+            Ok(serial) => Ok(serial as KeySerial),
+            Err(e) if e.raw_os_error() == Some(libc::ENOKEY) => Err(Failure::NotFound(format!(
+                "no {} keyring found, run `cred-lock init` to create one",
+                self.name
+            ))
+            .into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Lists the serial, profile and access key id of each stored key
+    fn keys(
+        &self,
+        keyring: KeySerial,
+    ) -> Result<Vec<StoredKey>, Box<dyn Error>> {
+        let serials = read(keyring)?;
+        let mut keys = Vec::new();
+        for chunk in serials.chunks_exact(4) {
+            let serial = KeySerial::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            // invalidated keys stay linked until garbage collected
+            let description = match describe(serial) {
+                Ok(description) => String::from_utf8(description)?,
+                Err(e) if gone(&e) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            // type;uid;gid;perm;description
+            let mut fields = description.splitn(5, ';');
+            if fields.next() != Some("user") {
+                continue;
+            }
+            if let Some((profile, access_key_id)) = fields
+                .nth(3)
+                .and_then(|description| description.rsplit_once(':'))
+            {
+                keys.push((serial, profile.into(), access_key_id.into()));
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Restarts the lock interval, failing if it has already run out
+    fn renew(&self) -> Result<(), Box<dyn Error>> {
+        match self.unlocked()? {
+            Some(marker) => {
+                keyctl(
+                    KEYCTL_SET_TIMEOUT,
+                    marker as c_ulong,
+                    c_ulong::from(self.lock_interval),
+                    0,
+                    0,
+                )?;
+                Ok(())
+            }
+            None => {
+                // the lock interval ran out, so catch the keys' permissions up
+                self.set_permissions(LOCKED)?;
+                Err(Failure::Locked("key store is locked, run `cred-lock unlock`".into()).into())
+            }
+        }
+    }
+
+    fn set_permissions(
+        &self,
+        permissions: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        for (serial, ..) in self.keys(self.find()?)? {
+            keyctl(
+                KEYCTL_SETPERM,
+                serial as c_ulong,
+                c_ulong::from(permissions),
+                0,
+                0,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl CredentialStore for KernelKeyring {
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.find().is_ok() {
+            return Err(
+                Failure::Duplicate(format!("a {} keyring already exists", self.name)).into(),
+            );
+        }
+        add_key("keyring", &self.name, None, self.parent)?;
+        self.unlock()
+    }
+
+    fn put(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        self.renew()?;
+        add_key(
+            "user",
+            &format!("{}:{}", entry.profile, entry.access_key_id),
+            Some(entry.secret_access_key.expose().as_bytes()),
+            self.find()?,
+        )?;
+        Ok(())
+    }
+
+    fn fetch(
+        &mut self,
+        profile: &str,
+    ) -> Result<Vec<Entry>, Box<dyn Error>> {
+        self.renew()?;
+        let mut entries = Vec::new();
+        for (serial, name, access_key_id) in self.keys(self.find()?)? {
+            if name != profile {
+                continue;
+            }
+            let secret = match read(serial) {
+                Ok(secret) => secret,
+                Err(e) if e.raw_os_error() == Some(libc::EACCES) => {
+                    return Err(Failure::Locked(
+                        "key store is locked, run `cred-lock unlock`".into(),
+                    )
+                    .into())
+                }
+                Err(e) => return Err(e.into()),
+            };
+            entries.push(Entry {
+                profile: name,
+                access_key_id,
+                secret_access_key: String::from_utf8(secret)?.into(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn delete(
+        &mut self,
+        profile: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        for (serial, name, _) in self.keys(self.find()?)? {
+            if name == profile {
+                keyctl(KEYCTL_INVALIDATE, serial as c_ulong, 0, 0, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        old: &Listing,
+        new: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        self.renew()?;
+        let keyring = self.find()?;
+        let (serial, ..) = self
+            .keys(keyring)?
+            .into_iter()
+            .find(|(_, profile, access_key_id)| old.lists(profile, access_key_id))
+            .ok_or_else(|| old.missing())?;
+        // adding a key with the description of an existing one updates it in place
+        let replacement = add_key(
+            "user",
+            &format!("{}:{}", new.profile, new.access_key_id),
+            Some(new.secret_access_key.expose().as_bytes()),
+            keyring,
+        )?;
+        if replacement != serial {
+            keyctl(KEYCTL_INVALIDATE, serial as c_ulong, 0, 0, 0)?;
+        }
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<Vec<Listing>, Box<dyn Error>> {
+        Ok(self
+            .keys(self.find()?)?
+            .into_iter()
+            .map(|(_, profile, access_key_id)| Listing {
+                profile,
+                access_key_id,
+            })
+            .collect())
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(marker) = self.unlocked()? {
+            keyctl(KEYCTL_INVALIDATE, marker as c_ulong, 0, 0, 0)?;
+        }
+        self.set_permissions(LOCKED)
+    }
+
+    fn unlock(&mut self) -> Result<(), Box<dyn Error>> {
+        self.set_permissions(UNLOCKED)?;
+        add_key("user", &self.marker(), Some(b"unlocked"), self.parent)?;
+        self.renew()
+    }
+}
+
+/// Whether an error means a key was invalidated, expired or never existed
+fn gone(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::ENOKEY | libc::EKEYEXPIRED | libc::EKEYREVOKED)
+    )
+}
+
+fn keyctl(
+    operation: c_long,
+    arg2: c_ulong,
+    arg3: c_ulong,
+    arg4: c_ulong,
+    arg5: c_ulong,
+) -> io::Result<c_long> {
+    match unsafe { libc::syscall(libc::SYS_keyctl, operation, arg2, arg3, arg4, arg5) } {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result),
+    }
+}
+
+fn add_key(
+    kind: &str,
+    description: &str,
+    payload: Option<&[u8]>,
+    keyring: KeySerial,
+) -> Result<KeySerial, Box<dyn Error>> {
+    let kind = CString::new(kind)?;
+    let description = CString::new(description)?;
+    let (payload, len) = payload.map_or((ptr::null(), 0), |payload| {
+        (payload.as_ptr(), payload.len())
+    });
+    match unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            kind.as_ptr(),
+            description.as_ptr(),
+            payload,
+            len,
+            keyring,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error().into()),
+        serial => Ok(serial as KeySerial),
+    }
+}
+
+fn read(serial: KeySerial) -> io::Result<Vec<u8>> {
+    read_with(KEYCTL_READ, serial)
+}
+
+fn describe(serial: KeySerial) -> io::Result<Vec<u8>> {
+    let mut description = read_with(KEYCTL_DESCRIBE, serial)?;
+    // descriptions are returned nul terminated
+    description.pop();
+    Ok(description)
+}
+
+/// Reads a key attribute, retrying if it grows between calls
+fn read_with(
+    operation: c_long,
+    serial: KeySerial,
+) -> io::Result<Vec<u8>> {
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        let len = keyctl(
+            operation,
+            serial as c_ulong,
+            buffer.as_mut_ptr() as c_ulong,
+            buffer.len() as c_ulong,
+            0,
+        )? as usize;
+        if len <= buffer.len() {
+            buffer.truncate(len);
+            return Ok(buffer);
+        }
+        buffer.resize(len, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error, store::memory::entry};
+    use std::{process, thread, time::Duration};
+
+    /// Tests keep their keyrings to the session they run in
+    const KEY_SPEC_SESSION_KEYRING: KeySerial = -3;
+
+    fn keyring(
+        name: &str,
+        lock_interval: u32,
+    ) -> KernelKeyring {
+        KernelKeyring {
+            name: format!("cred-lock-test-{}-{}", name, process::id()),
+            parent: KEY_SPEC_SESSION_KEYRING,
+            lock_interval,
+        }
+    }
+
+    fn remove(store: &mut KernelKeyring) -> Result<(), Box<dyn Error>> {
+        store.lock()?;
+        keyctl(KEYCTL_INVALIDATE, store.find()? as c_ulong, 0, 0, 0)?;
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_entries() -> Result<(), Box<dyn Error>> {
+        let mut store = keyring("round-trip", LOCK_INTERVAL);
+        store.init()?;
+        store.put(entry("dev", "key"))?;
+        assert_eq!(store.fetch("dev")?, vec![entry("dev", "key")]);
+        assert_eq!(
+            store.list()?,
+            vec![Listing {
+                profile: "dev".into(),
+                access_key_id: "key".into(),
+            }]
+        );
+        let renamed = Entry {
+            profile: "renamed".into(),
+            access_key_id: "other".into(),
+            ..entry("dev", "key")
+        };
+        store.replace(
+            &Listing {
+                profile: "dev".into(),
+                access_key_id: "key".into(),
+            },
+            renamed.clone(),
+        )?;
+        assert!(store.fetch("dev")?.is_empty());
+        assert_eq!(store.fetch("renamed")?, vec![renamed]);
+        store.delete("renamed")?;
+        assert!(store.fetch("renamed")?.is_empty());
+        remove(&mut store)
+    }
+
+    #[test]
+    fn withholds_secrets_while_locked() -> Result<(), Box<dyn Error>> {
+        let mut store = keyring("lock", LOCK_INTERVAL);
+        store.init()?;
+        store.put(entry("dev", "key"))?;
+        store.lock()?;
+        assert!(store.fetch("dev").is_err());
+        assert_eq!(
+            store.list()?,
+            vec![Listing {
+                profile: "dev".into(),
+                access_key_id: "key".into(),
+            }]
+        );
+        store.unlock()?;
+        assert_eq!(store.fetch("dev")?, vec![entry("dev", "key")]);
+        remove(&mut store)
+    }
+
+    #[test]
+    fn locks_when_idle_without_discarding_credentials() -> Result<(), Box<dyn Error>> {
+        let mut store = keyring("idle", 1);
+        store.init()?;
+        store.put(entry("dev", "key"))?;
+        thread::sleep(Duration::from_millis(1500));
+        let error = store.fetch("dev").err().ok_or("expected an error")?;
+        assert!(error::is(error.as_ref(), Failure::Locked));
+        assert_eq!(store.list()?.len(), 1);
+        store.unlock()?;
+        assert_eq!(store.fetch("dev")?, vec![entry("dev", "key")]);
+        remove(&mut store)
+    }
+
+    #[test]
+    fn keeps_the_unlock_marker_beside_the_credentials() -> Result<(), Box<dyn Error>> {
+        let parent = add_key(
+            "keyring",
+            &format!("cred-lock-test-parent-{}", process::id()),
+            None,
+            KEY_SPEC_SESSION_KEYRING,
+        )?;
+        let mut store = KernelKeyring {
+            parent,
+            ..keyring("marker", LOCK_INTERVAL)
+        };
+        store.init()?;
+        store.unlock()?;
+        let marker = store.unlocked()?.ok_or("expected an unlock marker")?;
+        assert!(read(parent)?
+            .chunks_exact(4)
+            .any(
+                |chunk| KeySerial::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])
+                    == marker
+            ));
+        remove(&mut store)?;
+        keyctl(KEYCTL_INVALIDATE, parent as c_ulong, 0, 0, 0)?;
+        Ok(())
+    }
+}
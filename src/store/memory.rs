@@ -0,0 +1,116 @@
+//! In-memory key store, for exercising code built on cred-lock in tests
+
+use super::{CredentialStore, Entry, Listing};
+use crate::error::Failure;
+use std::error::Error;
+
+#[derive(Default)]
+pub struct Memory {
+    pub entries: Vec<Entry>,
+    pub locked: bool,
+}
+
+/// Credentials for a profile with a placeholder secret
+#[cfg(any(test, feature = "test-support"))]
+pub fn entry(
+    profile: &str,
+    access_key_id: &str,
+) -> Entry {
+    Entry {
+        profile: profile.into(),
+        access_key_id: access_key_id.into(),
+        secret_access_key: "secret".into(),
+    }
+}
+
+/// Profile metadata and a credentials cache kept in a directory of their own,
+/// rather than in the user's
+#[cfg(any(test, feature = "test-support"))]
+pub fn files(dir: &std::path::Path) -> (crate::metadata::MetadataFile, crate::cache::Cache) {
+    (
+        crate::metadata::MetadataFile::at(dir.join("metadata.json")),
+        crate::cache::Cache::at(dir.join("credentials.cache"), 300),
+    )
+}
+
+impl Memory {
+    fn check_unlocked(&self) -> Result<(), Box<dyn Error>> {
+        if self.locked {
+            return Err(Failure::Locked("key store is locked".into()).into());
+        }
+        Ok(())
+    }
+}
+
+impl CredentialStore for Memory {
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn put(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        self.check_unlocked()?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn fetch(
+        &mut self,
+        profile: &str,
+    ) -> Result<Vec<Entry>, Box<dyn Error>> {
+        self.check_unlocked()?;
+        Ok(self
+            .entries
+            .iter()
+            .filter(|entry| entry.profile == profile)
+            .cloned()
+            .collect())
+    }
+
+    fn delete(
+        &mut self,
+        profile: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.check_unlocked()?;
+        self.entries.retain(|entry| entry.profile != profile);
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        old: &Listing,
+        new: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        self.check_unlocked()?;
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| old.lists(&entry.profile, &entry.access_key_id))
+            .ok_or_else(|| old.missing())?;
+        *entry = new;
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<Vec<Listing>, Box<dyn Error>> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| Listing {
+                profile: entry.profile.clone(),
+                access_key_id: entry.access_key_id.clone(),
+            })
+            .collect())
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn Error>> {
+        self.locked = true;
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> Result<(), Box<dyn Error>> {
+        self.locked = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,645 @@
+//! freedesktop Secret Service backed key store
+//!
+//! Credentials live in a dedicated collection, labeled after the key store
+//! name, of whichever Secret Service provider owns the session bus name
+//! (GNOME Keyring, KWallet, KeePassXC...). Each item is labeled with its
+//! profile name and carries the profile and access key id as attributes,
+//! the way keychain items carry `labl` and `acct`. Secrets are exchanged
+//! with the provider using the `plain` algorithm, relying on the session
+//! bus only being reachable by the current user.
+
+use super::{CredentialStore, Entry, Listing};
+use crate::error::Failure;
+use std::{collections::HashMap, convert::TryFrom, error::Error, slice};
+use zbus::{
+    blocking::{connection, Connection, Proxy, ProxyBuilder},
+    zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value},
+    CacheProperties,
+};
+
+const DESTINATION: &str = "org.freedesktop.secrets";
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+const SERVICE: &str = "org.freedesktop.Secret.Service";
+const COLLECTION: &str = "org.freedesktop.Secret.Collection";
+const ITEM: &str = "org.freedesktop.Secret.Item";
+const PROMPT: &str = "org.freedesktop.Secret.Prompt";
+/// Attribute identifying items created by cred-lock
+const APPLICATION: &str = "cred-lock";
+
+/// (session, parameters, value, content type)
+type Secret = (OwnedObjectPath, Vec<u8>, Vec<u8>, String);
+
+pub struct SecretService {
+    label: String,
+    address: Option<String>,
+}
+
+/// A connection with an open Secret Service session
+struct Session {
+    connection: Connection,
+    path: OwnedObjectPath,
+}
+
+impl SecretService {
+    pub fn new(label: &str) -> Self {
+        SecretService {
+            label: label.into(),
+            address: None,
+        }
+    }
+
+    fn connect(&self) -> Result<Session, Box<dyn Error>> {
+        let connection = match &self.address {
+            Some(address) => connection::Builder::address(address.as_str())?.build()?,
+            None => Connection::session()?,
+        };
+        let (_, path): (OwnedValue, OwnedObjectPath) =
+            proxy(&connection, SERVICE_PATH, SERVICE)?
+                .call("OpenSession", &("plain", Value::from("")))?;
+        Ok(Session { connection, path })
+    }
+}
+
+impl Session {
+    fn proxy<'a>(
+        &'a self,
+        path: &'a str,
+        interface: &'static str,
+    ) -> Result<Proxy<'a>, Box<dyn Error>> {
+        Ok(proxy(&self.connection, path, interface)?)
+    }
+
+    /// Finds the collection labeled `label`
+    fn collection(
+        &self,
+        label: &str,
+    ) -> Result<Option<OwnedObjectPath>, Box<dyn Error>> {
+        let collections: Vec<OwnedObjectPath> = self
+            .proxy(SERVICE_PATH, SERVICE)?
+            .get_property("Collections")?;
+        for path in collections {
+            let candidate: String = self
+                .proxy(path.as_str(), COLLECTION)?
+                .get_property("Label")?;
+            if candidate == label {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    fn require_collection(
+        &self,
+        label: &str,
+    ) -> Result<OwnedObjectPath, Box<dyn Error>> {
+        self.collection(label)?.ok_or_else(|| {
+            Failure::NotFound(format!(
+                "no {} collection found, run `cred-lock init` to create one",
+                label
+            ))
+            .into()
+        })
+    }
+
+    fn search(
+        &self,
+        collection: &ObjectPath,
+        profile: Option<&str>,
+    ) -> Result<Vec<OwnedObjectPath>, Box<dyn Error>> {
+        let mut attributes = HashMap::new();
+        attributes.insert("application", APPLICATION);
+        if let Some(profile) = profile {
+            attributes.insert("profile", profile);
+        }
+        Ok(self
+            .proxy(collection.as_str(), COLLECTION)?
+            .call("SearchItems", &(attributes,))?)
+    }
+
+    fn attributes(
+        &self,
+        item: &ObjectPath,
+    ) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        Ok(self
+            .proxy(item.as_str(), ITEM)?
+            .get_property("Attributes")?)
+    }
+
+    /// Creates an item for a set of credentials, replacing any with the same
+    /// profile and access key id
+    fn create(
+        &self,
+        collection: &OwnedObjectPath,
+        entry: &Entry,
+    ) -> Result<OwnedObjectPath, Box<dyn Error>> {
+        self.set_locked(slice::from_ref(collection), false)?;
+        let mut attributes = HashMap::new();
+        attributes.insert("application", APPLICATION);
+        attributes.insert("profile", entry.profile.as_str());
+        attributes.insert("account", entry.access_key_id.as_str());
+        let mut properties = HashMap::new();
+        properties.insert(
+            "org.freedesktop.Secret.Item.Label",
+            Value::from(entry.profile.as_str()),
+        );
+        properties.insert(
+            "org.freedesktop.Secret.Item.Attributes",
+            Value::from(attributes),
+        );
+        // borrowed rather than copied into a buffer that would outlive the call unzeroed
+        let secret = (
+            &self.path,
+            &[] as &[u8],
+            entry.secret_access_key.expose().as_bytes(),
+            "text/plain",
+        );
+        let (item, prompt): (OwnedObjectPath, OwnedObjectPath) = self
+            .proxy(collection.as_str(), COLLECTION)?
+            .call("CreateItem", &(properties, secret, true))?;
+        // items needing a prompt are only created once it completes
+        match self.complete(&prompt)? {
+            Some(created) => Ok(OwnedObjectPath::try_from(created)?),
+            None => Ok(item),
+        }
+    }
+
+    /// Locks or unlocks objects, completing any prompt the provider requires
+    fn set_locked(
+        &self,
+        objects: &[OwnedObjectPath],
+        locked: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let method = if locked { "Lock" } else { "Unlock" };
+        let (_, prompt): (Vec<OwnedObjectPath>, OwnedObjectPath) = self
+            .proxy(SERVICE_PATH, SERVICE)?
+            .call(method, &(objects,))?;
+        self.complete(&prompt)?;
+        Ok(())
+    }
+
+    /// Shows a prompt if one was requested, waiting for the user to complete it
+    fn complete(
+        &self,
+        prompt: &ObjectPath,
+    ) -> Result<Option<OwnedValue>, Box<dyn Error>> {
+        if prompt.as_str() == "/" {
+            return Ok(None);
+        }
+        let proxy = self.proxy(prompt.as_str(), PROMPT)?;
+        let mut completed = proxy.receive_signal("Completed")?;
+        proxy.call::<_, _, ()>("Prompt", &("",))?;
+        let signal = completed
+            .next()
+            .ok_or_else(|| Failure::UserCancelled("secret service prompt was closed".into()))?;
+        let (dismissed, result): (bool, OwnedValue) = signal.body().deserialize()?;
+        if dismissed {
+            return Err(
+                Failure::UserCancelled("secret service prompt was dismissed".into()).into(),
+            );
+        }
+        Ok(Some(result))
+    }
+}
+
+impl CredentialStore for SecretService {
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        let session = self.connect()?;
+        if session.collection(&self.label)?.is_some() {
+            return Err(
+                Failure::Duplicate(format!("a {} collection already exists", self.label)).into(),
+            );
+        }
+        let mut properties = HashMap::new();
+        properties.insert(
+            "org.freedesktop.Secret.Collection.Label",
+            Value::from(self.label.as_str()),
+        );
+        let (_, prompt): (OwnedObjectPath, OwnedObjectPath) = session
+            .proxy(SERVICE_PATH, SERVICE)?
+            .call("CreateCollection", &(properties, ""))?;
+        session.complete(&prompt)?;
+        Ok(())
+    }
+
+    fn put(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        let session = self.connect()?;
+        let collection = session.require_collection(&self.label)?;
+        session.create(&collection, &entry)?;
+        Ok(())
+    }
+
+    fn fetch(
+        &mut self,
+        profile: &str,
+    ) -> Result<Vec<Entry>, Box<dyn Error>> {
+        let session = self.connect()?;
+        let collection = session.require_collection(&self.label)?;
+        let items = session.search(&collection, Some(profile))?;
+        if items.is_empty() {
+            return Ok(Vec::new());
+        }
+        session.set_locked(&items, false)?;
+        let mut secrets: HashMap<OwnedObjectPath, Secret> =
+            session
+                .proxy(SERVICE_PATH, SERVICE)?
+                .call("GetSecrets", &(&items, &session.path))?;
+        let mut entries = Vec::new();
+        for item in items {
+            let (_, _, secret, _) = secrets.remove(&item).ok_or_else(|| {
+                Failure::Locked("key store is locked, run `cred-lock unlock`".into())
+            })?;
+            entries.push(Entry {
+                profile: profile.into(),
+                access_key_id: session
+                    .attributes(&item)?
+                    .remove("account")
+                    .unwrap_or_default(),
+                secret_access_key: String::from_utf8(secret)?.into(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn delete(
+        &mut self,
+        profile: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let session = self.connect()?;
+        let collection = session.require_collection(&self.label)?;
+        for item in session.search(&collection, Some(profile))? {
+            let prompt: OwnedObjectPath =
+                session.proxy(item.as_str(), ITEM)?.call("Delete", &())?;
+            session.complete(&prompt)?;
+        }
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        old: &Listing,
+        new: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        let session = self.connect()?;
+        let collection = session.require_collection(&self.label)?;
+        let mut stale = Vec::new();
+        for item in session.search(&collection, Some(&old.profile))? {
+            if session.attributes(&item)?.get("account") == Some(&old.access_key_id) {
+                stale.push(item);
+            }
+        }
+        if stale.is_empty() {
+            return Err(old.missing());
+        }
+        // items with the same attributes are replaced in place by the provider
+        let replacement = session.create(&collection, &new)?;
+        for item in stale.into_iter().filter(|item| item != &replacement) {
+            let prompt: OwnedObjectPath =
+                session.proxy(item.as_str(), ITEM)?.call("Delete", &())?;
+            session.complete(&prompt)?;
+        }
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<Vec<Listing>, Box<dyn Error>> {
+        let session = self.connect()?;
+        let collection = session.require_collection(&self.label)?;
+        let mut listings = Vec::new();
+        for item in session.search(&collection, None)? {
+            let mut attributes = session.attributes(&item)?;
+            if let Some(profile) = attributes.remove("profile") {
+                listings.push(Listing {
+                    profile,
+                    access_key_id: attributes.remove("account").unwrap_or_default(),
+                });
+            }
+        }
+        Ok(listings)
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn Error>> {
+        let session = self.connect()?;
+        let collection = session.require_collection(&self.label)?;
+        session.set_locked(&[collection], true)
+    }
+
+    fn unlock(&mut self) -> Result<(), Box<dyn Error>> {
+        let session = self.connect()?;
+        let collection = session.require_collection(&self.label)?;
+        session.set_locked(&[collection], false)
+    }
+}
+
+fn proxy<'a>(
+    connection: &Connection,
+    path: &'a str,
+    interface: &'static str,
+) -> zbus::Result<Proxy<'a>> {
+    ProxyBuilder::new(connection)
+        .destination(DESTINATION)?
+        .path(path)?
+        .interface(interface)?
+        .cache_properties(CacheProperties::No)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::entry;
+    use std::{
+        convert::TryFrom,
+        io::{self, BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        sync::{Arc, Mutex},
+    };
+    use zbus::{fdo, interface, ObjectServer};
+
+    const COLLECTION_PATH: &str = "/org/freedesktop/secrets/collection/test";
+
+    /// A private session bus torn down when dropped
+    struct Bus(Child, String);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Starts a private session bus, or returns `None` when dbus-daemon is not on PATH
+    fn bus() -> Result<Option<Bus>, Box<dyn Error>> {
+        let spawned = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut daemon = match spawned {
+            Ok(daemon) => daemon,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                eprintln!("skipping, dbus-daemon is not on PATH to run a stand-in Secret Service");
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().ok_or("no dbus-daemon output")?)
+            .read_line(&mut address)?;
+        Ok(Some(Bus(daemon, address.trim().into())))
+    }
+
+    #[derive(Default)]
+    struct State {
+        label: Option<String>,
+        locked: bool,
+        next: u32,
+        items: Vec<(OwnedObjectPath, HashMap<String, String>, Vec<u8>)>,
+    }
+
+    type Shared = Arc<Mutex<State>>;
+
+    fn path(path: &str) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(path).expect("valid object path")
+    }
+
+    /// Stand-in for a Secret Service provider, never requiring prompts
+    struct Service(Shared);
+
+    #[interface(name = "org.freedesktop.Secret.Service")]
+    impl Service {
+        fn open_session(
+            &self,
+            _algorithm: &str,
+            _input: Value<'_>,
+        ) -> (Value<'static>, OwnedObjectPath) {
+            (Value::from(""), path("/org/freedesktop/secrets/session/1"))
+        }
+
+        async fn create_collection(
+            &self,
+            mut properties: HashMap<String, OwnedValue>,
+            _alias: &str,
+            #[zbus(object_server)] server: &ObjectServer,
+        ) -> fdo::Result<(OwnedObjectPath, OwnedObjectPath)> {
+            let label = properties
+                .remove("org.freedesktop.Secret.Collection.Label")
+                .ok_or_else(|| fdo::Error::InvalidArgs("missing label".into()))?;
+            self.0.lock().unwrap().label =
+                Some(String::try_from(label).map_err(zbus::Error::from)?);
+            server
+                .at(COLLECTION_PATH, Collection(self.0.clone()))
+                .await?;
+            Ok((path(COLLECTION_PATH), path("/")))
+        }
+
+        #[zbus(property)]
+        fn collections(&self) -> Vec<OwnedObjectPath> {
+            match self.0.lock().unwrap().label {
+                Some(_) => vec![path(COLLECTION_PATH)],
+                None => Vec::new(),
+            }
+        }
+
+        fn lock(
+            &self,
+            objects: Vec<OwnedObjectPath>,
+        ) -> (Vec<OwnedObjectPath>, OwnedObjectPath) {
+            self.0.lock().unwrap().locked = true;
+            (objects, path("/"))
+        }
+
+        fn unlock(
+            &self,
+            objects: Vec<OwnedObjectPath>,
+        ) -> (Vec<OwnedObjectPath>, OwnedObjectPath) {
+            self.0.lock().unwrap().locked = false;
+            (objects, path("/"))
+        }
+
+        fn get_secrets(
+            &self,
+            items: Vec<OwnedObjectPath>,
+            session: OwnedObjectPath,
+        ) -> fdo::Result<HashMap<OwnedObjectPath, Secret>> {
+            let state = self.0.lock().unwrap();
+            if state.locked {
+                return Err(fdo::Error::AccessDenied("collection is locked".into()));
+            }
+            Ok(state
+                .items
+                .iter()
+                .filter(|(item, ..)| items.contains(item))
+                .map(|(item, _, secret)| {
+                    (
+                        item.clone(),
+                        (
+                            session.clone(),
+                            Vec::new(),
+                            secret.clone(),
+                            "text/plain".into(),
+                        ),
+                    )
+                })
+                .collect())
+        }
+    }
+
+    struct Collection(Shared);
+
+    #[interface(name = "org.freedesktop.Secret.Collection")]
+    impl Collection {
+        #[zbus(property)]
+        fn label(&self) -> String {
+            self.0.lock().unwrap().label.clone().unwrap_or_default()
+        }
+
+        fn search_items(
+            &self,
+            attributes: HashMap<String, String>,
+        ) -> Vec<OwnedObjectPath> {
+            self.0
+                .lock()
+                .unwrap()
+                .items
+                .iter()
+                .filter(|(_, stored, _)| {
+                    attributes
+                        .iter()
+                        .all(|(name, value)| stored.get(name) == Some(value))
+                })
+                .map(|(item, ..)| item.clone())
+                .collect()
+        }
+
+        async fn create_item(
+            &self,
+            mut properties: HashMap<String, OwnedValue>,
+            secret: Secret,
+            replace: bool,
+            #[zbus(object_server)] server: &ObjectServer,
+        ) -> fdo::Result<(OwnedObjectPath, OwnedObjectPath)> {
+            let attributes = properties
+                .remove("org.freedesktop.Secret.Item.Attributes")
+                .ok_or_else(|| fdo::Error::InvalidArgs("missing attributes".into()))?;
+            let attributes =
+                HashMap::<String, String>::try_from(attributes).map_err(zbus::Error::from)?;
+            let item = {
+                let mut state = self.0.lock().unwrap();
+                if let Some((item, _, stored)) = state
+                    .items
+                    .iter_mut()
+                    .find(|(_, stored, _)| replace && stored == &attributes)
+                {
+                    *stored = secret.2;
+                    return Ok((item.clone(), path("/")));
+                }
+                state.next += 1;
+                let item = path(&format!("{}/{}", COLLECTION_PATH, state.next));
+                state.items.push((item.clone(), attributes, secret.2));
+                item
+            };
+            server
+                .at(
+                    item.clone(),
+                    Item {
+                        state: self.0.clone(),
+                        path: item.clone(),
+                    },
+                )
+                .await?;
+            Ok((item, path("/")))
+        }
+    }
+
+    struct Item {
+        state: Shared,
+        path: OwnedObjectPath,
+    }
+
+    #[interface(name = "org.freedesktop.Secret.Item")]
+    impl Item {
+        #[zbus(property)]
+        fn attributes(&self) -> HashMap<String, String> {
+            self.state
+                .lock()
+                .unwrap()
+                .items
+                .iter()
+                .find(|(item, ..)| item == &self.path)
+                .map(|(_, attributes, _)| attributes.clone())
+                .unwrap_or_default()
+        }
+
+        async fn delete(
+            &self,
+            #[zbus(object_server)] server: &ObjectServer,
+        ) -> fdo::Result<OwnedObjectPath> {
+            self.state
+                .lock()
+                .unwrap()
+                .items
+                .retain(|(item, ..)| item != &self.path);
+            server.remove::<Item, _>(&self.path).await?;
+            Ok(path("/"))
+        }
+    }
+
+    #[test]
+    fn round_trips_entries_through_a_stand_in_service() -> Result<(), Box<dyn Error>> {
+        let bus = match bus()? {
+            Some(bus) => bus,
+            None => return Ok(()),
+        };
+        let state = Shared::default();
+        let _service = connection::Builder::address(bus.1.as_str())?
+            .name(DESTINATION)?
+            .serve_at(SERVICE_PATH, Service(state.clone()))?
+            .build()?;
+        let mut store = SecretService {
+            label: "aws-credlock".into(),
+            address: Some(bus.1.clone()),
+        };
+        let entry = entry("dev", "key");
+
+        store.init()?;
+        store.put(entry.clone())?;
+        assert_eq!(store.fetch("dev")?, vec![entry.clone()]);
+        assert_eq!(
+            store.list()?,
+            vec![Listing {
+                profile: "dev".into(),
+                access_key_id: "key".into(),
+            }]
+        );
+
+        let listing = Listing {
+            profile: "dev".into(),
+            access_key_id: "key".into(),
+        };
+        let rotated = Entry {
+            secret_access_key: "rotated".into(),
+            ..entry.clone()
+        };
+        store.replace(&listing, rotated.clone())?;
+        assert_eq!(store.fetch("dev")?, vec![rotated]);
+        let renamed = Entry {
+            profile: "renamed".into(),
+            ..entry.clone()
+        };
+        store.replace(&listing, renamed.clone())?;
+        assert!(store.fetch("dev")?.is_empty());
+        assert_eq!(store.fetch("renamed")?, vec![renamed]);
+        assert!(store.replace(&listing, entry).is_err());
+
+        store.lock()?;
+        assert!(state.lock().unwrap().locked);
+        store.unlock()?;
+        assert!(!state.lock().unwrap().locked);
+
+        store.delete("renamed")?;
+        assert!(store.fetch("renamed")?.is_empty());
+        Ok(())
+    }
+}
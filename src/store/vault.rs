@@ -0,0 +1,690 @@
+//! Passphrase protected vault file backed key store
+//!
+//! The vault is a json document with a versioned header describing how
+//! its key is derived from the passphrase (argon2id) followed by one
+//! XChaCha20-Poly1305 sealed secret per entry, each with its own nonce.
+//! Profile names and access key ids are kept in the clear, like keychain
+//! item attributes, but are bound to their secret as associated data.
+//!
+//! Once unlocked, the derived key is kept in a user-only session file
+//! under the runtime directory until the vault has been idle for its
+//! lock interval, mirroring the keychain's auto-lock setting. The key is
+//! stored there unencrypted, so anything running as the user, or as root,
+//! can read the vault while it is unlocked.
+
+use super::{CredentialStore, Entry, Listing, DEFAULT_CHAIN, LOCK_INTERVAL};
+use crate::{atomic, error::Failure, secret::Secret};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use dialoguer::{theme::ColorfulTheme, PasswordInput};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    error::Error,
+    fs::{self, DirBuilder},
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use zeroize::Zeroizing;
+
+/// Current vault file format version
+const VERSION: u8 = 1;
+/// Plaintext sealed in the header to verify a passphrase
+const CHECK: &[u8] = b"cred-lock";
+
+/// A key derived from the vault passphrase, zeroed when dropped
+type VaultKey = Zeroizing<[u8; 32]>;
+
+/// Argon2id cost parameters used when creating a vault
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Kdf {
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u8,
+    kdf: Kdf,
+    salt: String,
+    lock_interval: u64,
+    check: Sealed,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Item {
+    profile: String,
+    access_key_id: String,
+    secret_access_key: Sealed,
+}
+
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+    header: Header,
+    entries: Vec<Item>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    salt: String,
+    expires: u64,
+    key: Secret,
+}
+
+type Passphrase = Box<dyn FnMut(bool) -> Result<String, Box<dyn Error>>>;
+
+pub struct Vault {
+    path: PathBuf,
+    session: Option<PathBuf>,
+    kdf: Kdf,
+    passphrase: Passphrase,
+    key: Option<VaultKey>,
+}
+
+impl Vault {
+    /// Opens the vault at `CRED_LOCK_VAULT`, defaulting to the user's data directory
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let path = match env::var_os("CRED_LOCK_VAULT") {
+            Some(path) => PathBuf::from(path),
+            None => dirs::data_dir()
+                .ok_or("unable to resolve a data directory for the vault")?
+                .join("cred-lock")
+                .join(format!("{}.vault", DEFAULT_CHAIN)),
+        };
+        let session = dirs::runtime_dir().map(|dir| {
+            dir.join("cred-lock")
+                .join(format!("{}.session", DEFAULT_CHAIN))
+        });
+        Ok(Vault {
+            path,
+            session,
+            kdf: Kdf::default(),
+            passphrase: Box::new(prompt),
+            key: None,
+        })
+    }
+
+    fn read(&self) -> Result<VaultFile, Box<dyn Error>> {
+        let file: VaultFile = match fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| {
+                // serde messages may quote the document, so only report where it broke
+                Failure::Backend(format!(
+                    "corrupted vault {} at line {} column {}",
+                    self.path.display(),
+                    e.line(),
+                    e.column()
+                ))
+            })?,
+            Err(_) => {
+                return Err(Failure::NotFound(format!(
+                    "no vault found at {}, run `cred-lock init` to create one",
+                    self.path.display()
+                ))
+                .into())
+            }
+        };
+        if file.header.version != VERSION {
+            return Err(format!(
+                "unsupported vault version {}, expected {}",
+                file.header.version, VERSION
+            )
+            .into());
+        }
+        Ok(file)
+    }
+
+    fn write(
+        &self,
+        file: &VaultFile,
+    ) -> Result<(), Box<dyn Error>> {
+        write_private(&self.path, &serde_json::to_vec_pretty(file)?)
+    }
+
+    /// Holds off other processes changing the vault until dropped, so that
+    /// reading, changing and writing it back never loses their entries
+    fn exclusive(&self) -> Result<atomic::Lock, Box<dyn Error>> {
+        if let Some(parent) = self.path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+        Ok(atomic::Lock::exclusive(&self.path)?)
+    }
+
+    /// Resolves the vault key from memory, a live session or the passphrase
+    fn key(
+        &mut self,
+        header: &Header,
+    ) -> Result<VaultKey, Box<dyn Error>> {
+        let key = match self.key.clone().or_else(|| self.session_key(header)) {
+            Some(key) => key,
+            None => {
+                let passphrase = Zeroizing::new((self.passphrase)(false)?);
+                let key = derive(&passphrase, &header.kdf, &decode(&header.salt)?)?;
+                open(&key, &header.check, header.version, &[])
+                    .map_err(|_| Failure::Locked("incorrect passphrase".into()))?;
+                key
+            }
+        };
+        self.key = Some(key.clone());
+        self.touch_session(header, &key)?;
+        Ok(key)
+    }
+
+    fn session_key(
+        &self,
+        header: &Header,
+    ) -> Option<VaultKey> {
+        let bytes = Zeroizing::new(fs::read(self.session.as_ref()?).ok()?);
+        let session: Session = serde_json::from_slice(&bytes).ok()?;
+        if session.salt != header.salt || session.expires <= now() {
+            return None;
+        }
+        let decoded = Zeroizing::new(decode(session.key.expose()).ok()?);
+        if decoded.len() != 32 {
+            return None;
+        }
+        let mut key = VaultKey::default();
+        key.copy_from_slice(&decoded);
+        open(&key, &header.check, header.version, &[]).ok()?;
+        Some(key)
+    }
+
+    /// Extends the session so the vault locks once idle for its lock interval
+    fn touch_session(
+        &self,
+        header: &Header,
+        key: &[u8; 32],
+    ) -> Result<(), Box<dyn Error>> {
+        let path = match &self.session {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(parent) = path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+        let session = Zeroizing::new(serde_json::to_vec(&Session {
+            salt: header.salt.clone(),
+            expires: now() + header.lock_interval,
+            key: STANDARD.encode(key).into(),
+        })?);
+        write_private(path, &session)
+    }
+}
+
+impl CredentialStore for Vault {
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        let _lock = self.exclusive()?;
+        if self.path.exists() {
+            return Err(Failure::Duplicate(format!(
+                "a vault already exists at {}",
+                self.path.display()
+            ))
+            .into());
+        }
+        let passphrase = Zeroizing::new((self.passphrase)(true)?);
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let key = derive(&passphrase, &self.kdf, &salt)?;
+        let header = Header {
+            version: VERSION,
+            kdf: self.kdf,
+            salt: STANDARD.encode(salt),
+            lock_interval: u64::from(LOCK_INTERVAL),
+            check: seal(&key, CHECK, VERSION, &[])?,
+        };
+        self.write(&VaultFile {
+            header,
+            entries: Vec::new(),
+        })
+    }
+
+    fn put(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        let _lock = self.exclusive()?;
+        let mut file = self.read()?;
+        let key = self.key(&file.header)?;
+        let secret_access_key = seal(
+            &key,
+            entry.secret_access_key.expose().as_bytes(),
+            file.header.version,
+            &[&entry.profile, &entry.access_key_id],
+        )?;
+        file.entries.push(Item {
+            profile: entry.profile,
+            access_key_id: entry.access_key_id,
+            secret_access_key,
+        });
+        self.write(&file)
+    }
+
+    fn fetch(
+        &mut self,
+        profile: &str,
+    ) -> Result<Vec<Entry>, Box<dyn Error>> {
+        let file = self.read()?;
+        let key = self.key(&file.header)?;
+        let version = file.header.version;
+        file.entries
+            .into_iter()
+            .filter(|item| item.profile == profile)
+            .map(|item| {
+                let secret = open(
+                    &key,
+                    &item.secret_access_key,
+                    version,
+                    &[&item.profile, &item.access_key_id],
+                )?;
+                Ok(Entry {
+                    secret_access_key: String::from_utf8(secret)?.into(),
+                    profile: item.profile,
+                    access_key_id: item.access_key_id,
+                })
+            })
+            .collect()
+    }
+
+    fn delete(
+        &mut self,
+        profile: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let _lock = self.exclusive()?;
+        let mut file = self.read()?;
+        self.key(&file.header)?;
+        file.entries.retain(|item| item.profile != profile);
+        self.write(&file)
+    }
+
+    fn replace(
+        &mut self,
+        old: &Listing,
+        new: Entry,
+    ) -> Result<(), Box<dyn Error>> {
+        // the vault is rewritten in one go, so the swap is atomic
+        let _lock = self.exclusive()?;
+        let mut file = self.read()?;
+        let key = self.key(&file.header)?;
+        let index = file
+            .entries
+            .iter()
+            .position(|item| old.lists(&item.profile, &item.access_key_id))
+            .ok_or_else(|| old.missing())?;
+        let secret_access_key = seal(
+            &key,
+            new.secret_access_key.expose().as_bytes(),
+            file.header.version,
+            &[&new.profile, &new.access_key_id],
+        )?;
+        file.entries[index] = Item {
+            profile: new.profile,
+            access_key_id: new.access_key_id,
+            secret_access_key,
+        };
+        self.write(&file)
+    }
+
+    fn list(&mut self) -> Result<Vec<Listing>, Box<dyn Error>> {
+        Ok(self
+            .read()?
+            .entries
+            .into_iter()
+            .map(|item| Listing {
+                profile: item.profile,
+                access_key_id: item.access_key_id,
+            })
+            .collect())
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn Error>> {
+        self.key = None;
+        if let Some(path) = &self.session {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> Result<(), Box<dyn Error>> {
+        let file = self.read()?;
+        self.key(&file.header)?;
+        Ok(())
+    }
+}
+
+fn prompt(confirm: bool) -> Result<String, Box<dyn Error>> {
+    let theme = ColorfulTheme::default();
+    let mut input = PasswordInput::with_theme(&theme);
+    input
+        .with_prompt("🔐 Enter your vault passphrase")
+        .allow_empty_password(false);
+    if confirm {
+        input.with_confirmation(
+            "🔐 Confirm your vault passphrase",
+            "passphrases do not match",
+        );
+    }
+    input.interact().map_err(Failure::prompt)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(STANDARD.decode(value)?)
+}
+
+fn derive(
+    passphrase: &str,
+    kdf: &Kdf,
+    salt: &[u8],
+) -> Result<VaultKey, Box<dyn Error>> {
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(32))
+        .map_err(|e| format!("invalid vault kdf parameters: {}", e))?;
+    let mut key = VaultKey::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut_slice())
+        .map_err(|e| format!("failed to derive vault key: {}", e))?;
+    Ok(key)
+}
+
+/// Associated data binding a sealed value to the format version and its owner
+fn associated_data(
+    version: u8,
+    context: &[&str],
+) -> Vec<u8> {
+    let mut aad = vec![version];
+    for value in context {
+        aad.extend_from_slice(value.as_bytes());
+        aad.push(0);
+    }
+    aad
+}
+
+fn seal(
+    key: &[u8; 32],
+    plaintext: &[u8],
+    version: u8,
+    context: &[&str],
+) -> Result<Sealed, Box<dyn Error>> {
+    let mut nonce = [0; 24];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = XChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &associated_data(version, context),
+            },
+        )
+        .map_err(|_| "failed to encrypt vault entry")?;
+    Ok(Sealed {
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    })
+}
+
+fn open(
+    key: &[u8; 32],
+    sealed: &Sealed,
+    version: u8,
+    context: &[&str],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let nonce = decode(&sealed.nonce)?;
+    if nonce.len() != 24 {
+        return Err("corrupted vault entry".into());
+    }
+    Ok(XChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &decode(&sealed.ciphertext)?,
+                aad: &associated_data(version, context),
+            },
+        )
+        .map_err(|_| "failed to decrypt vault entry")?)
+}
+
+/// Atomically replaces a file with contents only readable by the current user.
+/// Each write goes through a temporary file of its own, as every fetch extends
+/// the session and credential processes run concurrently
+fn write_private(
+    path: &Path,
+    contents: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    Ok(atomic::write(path, contents, 0o600)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error;
+    use std::thread;
+
+    fn vault(dir: &Path) -> Vault {
+        Vault {
+            path: dir.join("test.vault"),
+            session: Some(dir.join("test.session")),
+            kdf: Kdf {
+                memory_kib: 64,
+                iterations: 1,
+                parallelism: 1,
+            },
+            passphrase: Box::new(|_| Ok("correct horse".into())),
+            key: None,
+        }
+    }
+
+    fn entry() -> Entry {
+        Entry {
+            profile: "dev".into(),
+            access_key_id: "key".into(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY".into(),
+        }
+    }
+
+    #[test]
+    fn round_trips_entries_without_storing_secrets_in_the_clear() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut store = vault(dir.path());
+        store.init()?;
+        store.put(entry())?;
+        assert!(!String::from_utf8(fs::read(&store.path)?)?.contains("wJalrXUtnFEMI"));
+        assert_eq!(store.fetch("dev")?, vec![entry()]);
+        assert_eq!(
+            store.list()?,
+            vec![Listing {
+                profile: "dev".into(),
+                access_key_id: "key".into(),
+            }]
+        );
+        let renamed = Entry {
+            profile: "renamed".into(),
+            access_key_id: "other".into(),
+            ..entry()
+        };
+        store.replace(
+            &Listing {
+                profile: "dev".into(),
+                access_key_id: "key".into(),
+            },
+            renamed.clone(),
+        )?;
+        assert!(store.fetch("dev")?.is_empty());
+        assert_eq!(store.fetch("renamed")?, vec![renamed]);
+        store.delete("renamed")?;
+        assert!(store.fetch("renamed")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_incorrect_passphrase_once_locked() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut store = vault(dir.path());
+        store.init()?;
+        store.put(entry())?;
+        store.lock()?;
+        store.passphrase = Box::new(|_| Ok("wrong".into()));
+        let error = store.fetch("dev").err().ok_or("expected an error")?;
+        assert!(error::is(error.as_ref(), Failure::Locked));
+        Ok(())
+    }
+
+    #[test]
+    fn reuses_session_until_idle_for_lock_interval() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut store = vault(dir.path());
+        store.init()?;
+        store.put(entry())?;
+
+        let mut reopened = vault(dir.path());
+        reopened.passphrase = Box::new(|_| Err("prompted".into()));
+        assert_eq!(reopened.fetch("dev")?, vec![entry()]);
+
+        let mut session: Session =
+            serde_json::from_slice(&fs::read(dir.path().join("test.session"))?)?;
+        session.expires = now() - 1;
+        fs::write(
+            dir.path().join("test.session"),
+            serde_json::to_vec(&session)?,
+        )?;
+        let mut expired = vault(dir.path());
+        expired.passphrase = Box::new(|_| Err("prompted".into()));
+        assert!(expired.fetch("dev").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_fetches_share_one_session() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut store = vault(dir.path());
+        store.init()?;
+        store.put(entry())?;
+
+        let fetches = (0..8)
+            .map(|_| {
+                let dir = dir.path().to_path_buf();
+                thread::spawn(move || {
+                    let mut reopened = vault(&dir);
+                    reopened.passphrase = Box::new(|_| Err("prompted".into()));
+                    (0..10).all(|_| {
+                        reopened
+                            .fetch("dev")
+                            .is_ok_and(|entries| entries == vec![entry()])
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        for fetch in fetches {
+            assert!(fetch.join().unwrap());
+        }
+        // no temporary files are left behind beside the vault, its lock and its session
+        let mut names = fs::read_dir(dir.path())?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<Vec<_>, _>>()?;
+        names.sort();
+        assert_eq!(
+            names,
+            vec![".test.vault.lock", "test.session", "test.vault"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_writers_keep_every_entry() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut store = vault(dir.path());
+        store.init()?;
+        store.put(entry())?;
+
+        let writers = (0..8)
+            .map(|i| {
+                let dir = dir.path().to_path_buf();
+                thread::spawn(move || {
+                    let mut reopened = vault(&dir);
+                    let profile = format!("profile{}", i);
+                    reopened
+                        .put(Entry {
+                            profile: profile.clone(),
+                            ..entry()
+                        })
+                        .and_then(|_| {
+                            reopened.replace(
+                                &Listing {
+                                    profile: profile.clone(),
+                                    access_key_id: "key".into(),
+                                },
+                                Entry {
+                                    profile,
+                                    access_key_id: "other".into(),
+                                    ..entry()
+                                },
+                            )
+                        })
+                        .map_err(|e| e.to_string())
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+        let listed = store.list()?;
+        assert_eq!(listed.len(), 9);
+        assert_eq!(
+            listed
+                .iter()
+                .filter(|listing| listing.access_key_id == "other")
+                .count(),
+            8
+        );
+        store.delete("dev")?;
+        assert_eq!(store.list()?.len(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn detects_tampered_entries() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut store = vault(dir.path());
+        store.init()?;
+        store.put(entry())?;
+        let mut file = store.read()?;
+        file.entries[0].access_key_id = "other".into();
+        store.write(&file)?;
+        assert!(store.fetch("dev").is_err());
+        Ok(())
+    }
+}